@@ -0,0 +1,544 @@
+use crate::database::{self, FeedConf, Target, Template};
+use crate::feeds::{self, FeedOptions};
+use crate::rss_utils;
+use crate::server::{error_response, json_response, State};
+
+use chrono::prelude::NaiveTime;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::info;
+use mysql::{Conn, Value};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::runtime::Handle;
+use tokio::task;
+
+/**
+ * How many deliveries are returned when no limit is given, and at most.
+ */
+const DELIVERIES_LIMIT: u32 = 50;
+const DELIVERIES_MAX_LIMIT: u32 = 1000;
+
+/**
+ * The body of a request that adds a feed.
+ */
+#[derive(Deserialize)]
+struct NewFeed {
+    url: String,
+    #[serde(flatten)]
+    options: FeedOptions,
+}
+
+/**
+ * The body of a request that changes a feed.
+ */
+#[derive(Deserialize)]
+struct FeedChanges {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(flatten)]
+    options: FeedOptions,
+}
+
+/**
+ * The body of a request that adds or changes a shared template, an empty
+ * template is stored as NULL so it is inherited again.
+ */
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TemplateOptions {
+    name: Option<String>,
+    title: Option<String>,
+    message: Option<String>,
+    update_title: Option<String>,
+    update_message: Option<String>,
+}
+
+/**
+ * The body of a request that adds or changes a notification target, empty
+ * quiet hours and a rate limit of 0 are stored as NULL.
+ */
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TargetOptions {
+    name: Option<String>,
+    push_url: Option<String>,
+    push_token: Option<String>,
+    quiet_start: Option<String>,
+    quiet_end: Option<String>,
+    rate_limit_minute: Option<u32>,
+}
+
+/**
+ * The config and state of a feed as json. The templates are the ones that
+ * are actually used, so they may come from a shared template.
+ */
+fn feed_json(feed: &FeedConf) -> serde_json::Value {
+    return json!({
+        "id": feed.id,
+        "url": feed.url,
+        "enabled": feed.enabled,
+        "target": feed.target,
+        "push_url": feed.push_url,
+        "push_token": feed.push_token,
+        "template": feed.template,
+        "title": feed.title,
+        "message": feed.message,
+        "update_title": feed.update_title,
+        "update_message": feed.update_message,
+        "priority": feed.priority,
+        "format": feed.format.name(),
+        "timezone": feed.timezone,
+        "max_length": feed.max_length,
+        "last_fetch": feed.last_fetch,
+        "last_error": feed.last_error,
+        "last_error_at": feed.last_error_at,
+    });
+}
+
+/**
+ * Read the json body of a request.
+ */
+async fn read_json<T: DeserializeOwned>(req: Request<Body>) -> Result<T, Response<Body>> {
+    let bytes;
+    match hyper::body::to_bytes(req.into_body()).await {
+        Ok(x) => bytes = x,
+        Err(e) => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Could not read body ({})", e),
+            ))
+        }
+    }
+    match serde_json::from_slice(&bytes) {
+        Ok(x) => return Ok(x),
+        Err(e) => {
+            return Err(error_response(
+                StatusCode::BAD_REQUEST,
+                &format!("Invalid json ({})", e),
+            ))
+        }
+    }
+}
+
+/**
+ * Get a URL-decoded query parameter of a request.
+ */
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    return req.uri().query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    });
+}
+
+/**
+ * A response with only an error status.
+ */
+fn status_response(status: StatusCode) -> Response<Body> {
+    return error_response(status, status.canonical_reason().unwrap_or("Error"));
+}
+
+fn not_found() -> Response<Body> {
+    return status_response(StatusCode::NOT_FOUND);
+}
+
+fn database_error() -> Response<Body> {
+    return error_response(StatusCode::INTERNAL_SERVER_ERROR, "Database error");
+}
+
+/**
+ * Get a single feed, or the status saying why we could not.
+ */
+fn find_feed(feed_id: u32, conn: &mut Conn) -> Result<FeedConf, StatusCode> {
+    let feeds = database::get_feeds(conn).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    return feeds
+        .into_iter()
+        .find(|x| x.id == feed_id)
+        .ok_or(StatusCode::NOT_FOUND);
+}
+
+async fn feeds(req: Request<Body>, id: Option<u32>, conn: &mut Conn) -> Response<Body> {
+    match (req.method().clone(), id) {
+        (Method::GET, None) => match database::get_feeds(conn) {
+            Some(x) => return json_response(StatusCode::OK, x.iter().map(feed_json).collect()),
+            None => return database_error(),
+        },
+        (Method::GET, Some(id)) => match find_feed(id, conn) {
+            Ok(x) => return json_response(StatusCode::OK, feed_json(&x)),
+            Err(e) => return status_response(e),
+        },
+        (Method::POST, None) => {
+            let new: NewFeed;
+            match read_json(req).await {
+                Ok(x) => new = x,
+                Err(e) => return e,
+            }
+            match feeds::create(&new.url, &new.options, conn).await {
+                Ok(Some(id)) => {
+                    info!("Added feed {} {:?}", id, new.url);
+                    return json_response(StatusCode::CREATED, json!({ "id": id }));
+                }
+                Ok(None) => return json_response(StatusCode::OK, json!({ "id": null })),
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+        }
+        (Method::PUT, Some(id)) => {
+            let changes: FeedChanges;
+            match read_json(req).await {
+                Ok(x) => changes = x,
+                Err(e) => return e,
+            }
+            match feeds::update(id, changes.url, &changes.options, conn).await {
+                Ok(true) => info!("Updated feed {}", id),
+                Ok(false) => return not_found(),
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+            match find_feed(id, conn) {
+                Ok(x) => return json_response(StatusCode::OK, feed_json(&x)),
+                Err(e) => return status_response(e),
+            }
+        }
+        (Method::DELETE, Some(id)) => {
+            if !database::delete_feed(id, conn) {
+                return not_found();
+            }
+            info!("Removed feed {}", id);
+            return json_response(StatusCode::OK, json!({ "id": id }));
+        }
+        _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/**
+ * Check the template options and turn them into column values.
+ */
+fn template_values(options: TemplateOptions) -> Result<Vec<(&'static str, Value)>, String> {
+    let mut values: Vec<(&'static str, Value)> = Vec::new();
+    if let Some(x) = options.name {
+        if x.trim().is_empty() {
+            return Err("The name of a template can not be empty".to_owned());
+        }
+        values.push(("name", x.trim().into()));
+    }
+    let templates = [
+        ("title", options.title),
+        ("message", options.message),
+        ("update_title", options.update_title),
+        ("update_message", options.update_message),
+    ];
+    for (column, template) in templates {
+        match template {
+            Some(x) if x.is_empty() => values.push((column, Value::NULL)),
+            Some(x) => {
+                rss_utils::validate_template(&x)
+                    .map_err(|e| format!("Invalid {} template: {}", column, e))?;
+                values.push((column, x.into()));
+            }
+            None => {}
+        }
+    }
+    return Ok(values);
+}
+
+fn find_template(template_id: u32, conn: &mut Conn) -> Result<Template, StatusCode> {
+    let templates = database::get_templates(conn).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    return templates
+        .into_iter()
+        .find(|x| x.id == template_id)
+        .ok_or(StatusCode::NOT_FOUND);
+}
+
+async fn templates(req: Request<Body>, id: Option<u32>, conn: &mut Conn) -> Response<Body> {
+    match (req.method().clone(), id) {
+        (Method::GET, None) => match database::get_templates(conn) {
+            Some(x) => return json_response(StatusCode::OK, json!(x)),
+            None => return database_error(),
+        },
+        (Method::GET, Some(id)) => match find_template(id, conn) {
+            Ok(x) => return json_response(StatusCode::OK, json!(x)),
+            Err(e) => return status_response(e),
+        },
+        (Method::POST, None) => {
+            let options: TemplateOptions;
+            match read_json(req).await {
+                Ok(x) => options = x,
+                Err(e) => return e,
+            }
+            if options.name.is_none() {
+                return error_response(StatusCode::BAD_REQUEST, "A template needs a name");
+            }
+            let values;
+            match template_values(options) {
+                Ok(x) => values = x,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+            match database::insert_template(&values, conn) {
+                Some(id) => return json_response(StatusCode::CREATED, json!({ "id": id })),
+                None => return database_error(),
+            }
+        }
+        (Method::PUT, Some(id)) => {
+            let options: TemplateOptions;
+            match read_json(req).await {
+                Ok(x) => options = x,
+                Err(e) => return e,
+            }
+            let values;
+            match template_values(options) {
+                Ok(x) => values = x,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+            if !database::update_template(id, &values, conn) {
+                return not_found();
+            }
+            match find_template(id, conn) {
+                Ok(x) => return json_response(StatusCode::OK, json!(x)),
+                Err(e) => return status_response(e),
+            }
+        }
+        (Method::DELETE, Some(id)) => {
+            if !database::delete_template(id, conn) {
+                return not_found();
+            }
+            return json_response(StatusCode::OK, json!({ "id": id }));
+        }
+        _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/**
+ * Check the target options and turn them into column values.
+ */
+fn target_values(options: TargetOptions) -> Result<Vec<(&'static str, Value)>, String> {
+    let mut values: Vec<(&'static str, Value)> = Vec::new();
+    let fields = [
+        ("name", options.name),
+        ("push_url", options.push_url),
+        ("push_token", options.push_token),
+    ];
+    for (column, field) in fields {
+        if let Some(x) = field {
+            if x.trim().is_empty() {
+                return Err(format!("The {} of a target can not be empty", column));
+            }
+            values.push((column, x.trim().into()));
+        }
+    }
+    let quiet_hours = [
+        ("quiet_start", options.quiet_start),
+        ("quiet_end", options.quiet_end),
+    ];
+    for (column, field) in quiet_hours {
+        match field {
+            Some(x) if x.trim().is_empty() => values.push((column, Value::NULL)),
+            Some(x) => {
+                if NaiveTime::parse_from_str(x.trim(), "%H:%M").is_err() {
+                    return Err(format!("Invalid {} {:?}, use HH:MM", column, x));
+                }
+                values.push((column, x.trim().into()));
+            }
+            None => {}
+        }
+    }
+    match options.rate_limit_minute {
+        Some(0) => values.push(("rate_limit_minute", Value::NULL)),
+        Some(x) => values.push(("rate_limit_minute", x.into())),
+        None => {}
+    }
+    return Ok(values);
+}
+
+fn find_target(target_id: u32, conn: &mut Conn) -> Result<Target, StatusCode> {
+    let targets = database::get_targets(conn).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    return targets
+        .into_iter()
+        .find(|x| x.id == target_id)
+        .ok_or(StatusCode::NOT_FOUND);
+}
+
+async fn targets(req: Request<Body>, id: Option<u32>, conn: &mut Conn) -> Response<Body> {
+    match (req.method().clone(), id) {
+        (Method::GET, None) => match database::get_targets(conn) {
+            Some(x) => return json_response(StatusCode::OK, json!(x)),
+            None => return database_error(),
+        },
+        (Method::GET, Some(id)) => match find_target(id, conn) {
+            Ok(x) => return json_response(StatusCode::OK, json!(x)),
+            Err(e) => return status_response(e),
+        },
+        (Method::POST, None) => {
+            let options: TargetOptions;
+            match read_json(req).await {
+                Ok(x) => options = x,
+                Err(e) => return e,
+            }
+            if options.name.is_none() || options.push_url.is_none() || options.push_token.is_none()
+            {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "A target needs a name, push_url and push_token",
+                );
+            }
+            let values;
+            match target_values(options) {
+                Ok(x) => values = x,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+            match database::insert_target(&values, conn) {
+                Some(id) => return json_response(StatusCode::CREATED, json!({ "id": id })),
+                None => return database_error(),
+            }
+        }
+        (Method::PUT, Some(id)) => {
+            let options: TargetOptions;
+            match read_json(req).await {
+                Ok(x) => options = x,
+                Err(e) => return e,
+            }
+            let values;
+            match target_values(options) {
+                Ok(x) => values = x,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e),
+            }
+            if !database::update_target(id, &values, conn) {
+                return not_found();
+            }
+            match find_target(id, conn) {
+                Ok(x) => return json_response(StatusCode::OK, json!(x)),
+                Err(e) => return status_response(e),
+            }
+        }
+        (Method::DELETE, Some(id)) => {
+            // Feeds without a target have no push url, so keep targets that
+            // are still used
+            match database::count_target_feeds(id, conn) {
+                Some(0) => {}
+                Some(x) => {
+                    return error_response(
+                        StatusCode::CONFLICT,
+                        &format!("The target is used by {} feeds", x),
+                    )
+                }
+                None => return database_error(),
+            }
+            if !database::delete_target(id, conn) {
+                return not_found();
+            }
+            return json_response(StatusCode::OK, json!({ "id": id }));
+        }
+        _ => return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+/**
+ * The fetch state of all feeds, and how many of them are failing.
+ */
+fn health(conn: &mut Conn) -> Response<Body> {
+    let feeds;
+    match database::get_feeds(conn) {
+        Some(x) => feeds = x,
+        None => return database_error(),
+    }
+    let failing = feeds
+        .iter()
+        .filter(|x| x.enabled && x.last_error.is_some())
+        .count();
+    let states: Vec<serde_json::Value> = feeds
+        .iter()
+        .map(|x| {
+            json!({
+                "id": x.id,
+                "url": x.url,
+                "enabled": x.enabled,
+                "last_fetch": x.last_fetch,
+                "last_error": x.last_error,
+                "last_error_at": x.last_error_at,
+            })
+        })
+        .collect();
+    return json_response(
+        StatusCode::OK,
+        json!({ "failing": failing, "feeds": states }),
+    );
+}
+
+/**
+ * The latest deliveries, `?feed=<id>` limits them to one feed and
+ * `?limit=<n>` sets how many are returned.
+ */
+fn deliveries(req: &Request<Body>, conn: &mut Conn) -> Response<Body> {
+    let mut feed_id = None;
+    if let Some(x) = query_param(req, "feed") {
+        match x.parse::<u32>() {
+            Ok(x) => feed_id = Some(x),
+            Err(_e) => return error_response(StatusCode::BAD_REQUEST, "Invalid feed"),
+        }
+    }
+    let mut limit = DELIVERIES_LIMIT;
+    if let Some(x) = query_param(req, "limit") {
+        match x.parse::<u32>() {
+            Ok(x) => limit = x.min(DELIVERIES_MAX_LIMIT),
+            Err(_e) => return error_response(StatusCode::BAD_REQUEST, "Invalid limit"),
+        }
+    }
+    match database::get_deliveries(feed_id, limit, conn) {
+        Some(x) => return json_response(StatusCode::OK, json!(x)),
+        None => return database_error(),
+    }
+}
+
+/**
+ * Handle a request to the admin API, the token is already checked.
+ */
+pub async fn handle(req: Request<Body>, state: &State) -> Response<Body> {
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let parts: Vec<&str> = path.split('/').skip(2).collect();
+    let mut id = None;
+    if let Some(x) = parts.get(1) {
+        match x.parse::<u32>() {
+            Ok(x) => id = Some(x),
+            Err(_e) => return not_found(),
+        }
+    }
+    if parts.len() > 2 {
+        return not_found();
+    }
+
+    if parts == ["fetch"] {
+        if req.method() != Method::POST {
+            return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+        }
+        info!("Fetch requested through the admin API");
+        state.fetch_now.notify_one();
+        return json_response(StatusCode::ACCEPTED, json!({ "fetching": true }));
+    }
+
+    // The mysql calls block, so the request is handled on the blocking thread
+    // pool, instead of holding up the workers serving the other requests
+    let rt = Handle::current();
+    let res = task::spawn_blocking(move || {
+        let mut conn = database::new_conn()?;
+        let parts: Vec<&str> = path.split('/').skip(2).collect();
+        let res = rt.block_on(async {
+            match parts.first() {
+                Some(&"feeds") => return feeds(req, id, &mut conn).await,
+                Some(&"templates") => return templates(req, id, &mut conn).await,
+                Some(&"targets") => return targets(req, id, &mut conn).await,
+                Some(&"health") if id.is_none() && req.method() == Method::GET => {
+                    return health(&mut conn)
+                }
+                Some(&"deliveries") if id.is_none() && req.method() == Method::GET => {
+                    return deliveries(&req, &mut conn)
+                }
+                _ => return not_found(),
+            }
+        });
+        return Some(res);
+    })
+    .await;
+    match res {
+        Ok(Some(x)) => return x,
+        _ => return database_error(),
+    }
+}
@@ -1,6 +1,15 @@
+use crate::dryrun;
+use crate::metrics;
+use crate::notify::Notification;
+use crate::rss_utils;
+
+use chrono::prelude::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
 use log::{debug, error, info, warn};
 use mysql::prelude::*;
 use mysql::*;
+use serde::Serialize;
+use serde_json::Map;
 use std::env;
 use std::process;
 
@@ -8,13 +17,315 @@ use std::process;
 pub struct FeedConf {
     pub id: u32,
     pub url: String,
+    pub enabled: bool,
     pub last_fetch: Option<i64>,
+    pub template: Option<String>,
     pub title: String,
     pub message: String,
+    pub target: Option<u32>,
+    pub push_url: String,
+    pub push_token: String,
+    pub priority: u32,
+    pub priority_rules: Vec<PriorityRule>,
+    pub timezone: String,
+    /// The parsed timezone, UTC if `timezone` is not valid
+    pub tz: Tz,
+    pub quiet_start: Option<String>,
+    pub quiet_end: Option<String>,
+    pub quiet_digest: bool,
+    pub quiet_override: Option<u32>,
+    pub rate_limit_cycle: Option<u32>,
+    pub rate_limit_hour: Option<u32>,
+    /// The per minute limit of the target the feed sends to
+    pub target_rate_limit: Option<u32>,
+    pub backfill: Backfill,
+    pub notify_updates: bool,
+    pub update_title: String,
+    pub update_message: String,
+    pub missing_value: String,
+    pub date_format: Option<String>,
+    pub format: Format,
+    pub max_length: Option<u32>,
+    pub big_image: bool,
+    pub feed_icon: bool,
+    pub extras: Option<Map<String, serde_json::Value>>,
+    pub strip_images: bool,
+    pub strip_iframes: bool,
+    pub collapse_whitespace: bool,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<i64>,
+    /// Problems with the config found when the feed was loaded, these are
+    /// reported as the feed error
+    pub config_error: Option<String>,
+}
+
+impl Default for FeedConf {
+    /**
+     * A feed config with the same defaults as the database columns.
+     */
+    fn default() -> FeedConf {
+        return FeedConf {
+            id: 0,
+            url: "".to_owned(),
+            enabled: true,
+            last_fetch: None,
+            template: None,
+            title: "{{title}}: {{entry.title}}".to_owned(),
+            message: "{{entry.summary}}".to_owned(),
+            target: None,
+            push_url: "".to_owned(),
+            push_token: "".to_owned(),
+            priority: 1,
+            priority_rules: Vec::new(),
+            timezone: "UTC".to_owned(),
+            tz: Tz::UTC,
+            quiet_start: None,
+            quiet_end: None,
+            quiet_digest: false,
+            quiet_override: None,
+            rate_limit_cycle: None,
+            rate_limit_hour: None,
+            target_rate_limit: None,
+            backfill: Backfill::None,
+            notify_updates: false,
+            update_title: "Updated: {{entry.title}}".to_owned(),
+            update_message: "{{entry.summary}}".to_owned(),
+            missing_value: "".to_owned(),
+            date_format: None,
+            format: Format::Markdown,
+            max_length: None,
+            big_image: true,
+            feed_icon: false,
+            extras: None,
+            strip_images: false,
+            strip_iframes: true,
+            collapse_whitespace: true,
+            last_error: None,
+            last_error_at: None,
+            config_error: None,
+        };
+    }
+}
+
+/**
+ * A named gotify server and app token that feeds can send to.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Target {
+    pub id: u32,
+    pub name: String,
     pub push_url: String,
     pub push_token: String,
+    pub quiet_start: Option<String>,
+    pub quiet_end: Option<String>,
+    pub rate_limit_minute: Option<u32>,
+}
+
+/**
+ * A named set of templates that feeds can share, templates that are not set
+ * are inherited.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Template {
+    pub id: u32,
+    pub name: String,
+    pub title: Option<String>,
+    pub message: Option<String>,
+    pub update_title: Option<String>,
+    pub update_message: Option<String>,
+}
+
+/**
+ * A notification we sent, tried to send or queued.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Delivery {
+    pub id: u32,
+    pub feed_id: u32,
+    pub title: String,
+    pub priority: u32,
+    pub status: String,
+    pub error: Option<String>,
+    pub time: i64,
+}
+
+/**
+ * The format notifications are rendered in, HTML from the feed is converted
+ * to markdown, sanitized or stripped to plain text accordingly.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Markdown,
+    Html,
+    Plain,
+}
+
+impl Format {
+    /**
+     * Parse a format name, which is either `markdown`, `html` or `plain`.
+     */
+    pub fn parse(format: &str) -> Option<Format> {
+        match format.trim().to_lowercase().as_str() {
+            "markdown" => return Some(Format::Markdown),
+            "html" => return Some(Format::Html),
+            "plain" => return Some(Format::Plain),
+            _ => return None,
+        }
+    }
+
+    /**
+     * The name of the format, as it is stored in the database.
+     */
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Markdown => return "markdown",
+            Format::Html => return "html",
+            Format::Plain => return "plain",
+        }
+    }
+}
+
+/**
+ * Gotify priorities go from 0 to 10, anything outside of that is clamped to
+ * that range.
+ */
+fn parse_priority(priority: i32, what: &str, errors: &mut Vec<String>) -> u32 {
+    let clamped = priority.clamp(0, 10);
+    if clamped != priority {
+        errors.push(format!(
+            "Invalid {} ({}), using {}",
+            what, priority, clamped
+        ));
+    }
+    return clamped as u32;
+}
+
+/**
+ * Numbers in signed columns that can not be negative, a negative value is
+ * ignored as if the column was NULL.
+ */
+fn parse_unsigned(value: Option<i32>, what: &str, errors: &mut Vec<String>) -> Option<u32> {
+    let value = value?;
+    if value < 0 {
+        errors.push(format!("Invalid {} ({}), ignoring it", what, value));
+        return None;
+    }
+    return Some(value as u32);
+}
+
+/**
+ * Parse the timezone column, falls back to UTC if it is not a valid IANA
+ * timezone name.
+ */
+fn parse_timezone(timezone: &str, errors: &mut Vec<String>) -> Tz {
+    match timezone.parse::<Tz>() {
+        Ok(x) => return x,
+        Err(e) => {
+            errors.push(format!(
+                "Invalid timezone {:?}, using UTC ({})",
+                timezone, e
+            ));
+            return Tz::UTC;
+        }
+    }
+}
+
+/**
+ * Parse the format column, falls back to markdown if it is not valid.
+ */
+fn parse_format(feed_id: u32, format: String) -> Format {
+    match Format::parse(&format) {
+        Some(x) => return x,
+        None => {
+            warn!(
+                "Invalid format {:?} for feed {}, using markdown",
+                format, feed_id
+            );
+            return Format::Markdown;
+        }
+    }
+}
+
+/**
+ * Parse the extras column, which has to be a JSON object. Invalid extras are
+ * ignored.
+ */
+fn parse_extras(feed_id: u32, extras: Option<String>) -> Option<Map<String, serde_json::Value>> {
+    let extras = extras?;
+    match serde_json::from_str(&extras) {
+        Ok(serde_json::Value::Object(x)) => return Some(x),
+        Ok(_) => warn!(
+            "Extras of feed {} are not a JSON object, ignoring them",
+            feed_id
+        ),
+        Err(e) => warn!(
+            "Extras of feed {} are not valid JSON, ignoring them ({})",
+            feed_id, e
+        ),
+    }
+    return None;
+}
+
+/**
+ * What to send on the first successful fetch of a feed (when last_fetch is
+ * NULL).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Backfill {
+    None,
+    Latest(usize),
+    Since(DateTime<Utc>),
+}
+
+/**
+ * Parse the backfill column, which is either `none`, `latest:<N>` or
+ * `since:<date>` where date is `YYYY-MM-DD` or RFC 3339.
+ */
+fn parse_backfill(feed_id: u32, backfill: Option<String>) -> Backfill {
+    let backfill = match backfill {
+        Some(x) => x,
+        None => return Backfill::None,
+    };
+    if backfill.trim().eq_ignore_ascii_case("none") || backfill.trim().is_empty() {
+        return Backfill::None;
+    }
+    if let Some(n) = backfill.strip_prefix("latest:") {
+        if let Ok(n) = n.trim().parse::<usize>() {
+            return Backfill::Latest(n);
+        }
+    }
+    if let Some(date) = backfill.strip_prefix("since:") {
+        let date = date.trim();
+        if let Ok(x) = DateTime::parse_from_rfc3339(date) {
+            return Backfill::Since(x.with_timezone(&Utc));
+        }
+        if let Some(x) = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+            .ok()
+            .and_then(|x| x.and_hms_opt(0, 0, 0))
+        {
+            return Backfill::Since(DateTime::from_utc(x, Utc));
+        }
+    }
+    warn!(
+        "Invalid backfill {:?} for feed {}, not backfilling",
+        backfill, feed_id
+    );
+    return Backfill::None;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PriorityRule {
+    pub feed_id: Option<u32>,
+    pub field: String,
+    pub pattern: String,
+    pub priority: u32,
 }
 
+/**
+ * The database version this build expects, bump this when adding migrations.
+ */
+const DB_VERSION: i64 = 18;
+
 /**
  * Create Opts struct from env vars.
  */
@@ -35,6 +346,7 @@ fn build_opts() -> Opts {
 pub fn new_conn() -> Option<Conn> {
     let conn_res = Conn::new(build_opts());
     if let Err(ref x) = conn_res {
+        metrics::db_error();
         error!("Could not connect to database ({:#?})...", x);
         return None;
     }
@@ -119,6 +431,29 @@ fn get_db_version(conn: &mut Conn) -> i64 {
     return res_res.unwrap();
 }
 
+/**
+ * Check that the database can be reached and is migrated to the version this
+ * build expects. Unlike bootstrap this does not exit when something is wrong.
+ */
+pub fn check_ready() -> std::result::Result<(), String> {
+    let mut conn = new_conn().ok_or("Could not connect to the database")?;
+    let q = "SELECT `last_fetch` from `rss-watcher-feeds` WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version'";
+    match conn.query_first::<i64, _>(q) {
+        Ok(Some(x)) if x == DB_VERSION => return Ok(()),
+        Ok(Some(x)) => {
+            return Err(format!(
+                "The database is at version {}, expected {}",
+                x, DB_VERSION
+            ))
+        }
+        Ok(None) => return Err("The database has no version row".to_owned()),
+        Err(x) => {
+            metrics::db_error();
+            return Err(format!("Could not get the database version ({})", x));
+        }
+    }
+}
+
 /**
  * Run migrations v2.
  */
@@ -144,103 +479,1418 @@ fn run_migrations_v2(tx: &mut Transaction, version: i64) {
 }
 
 /**
- * Bootstrap the database, this will make sure tables exists,
- * create them if not and run migrations if nececarry.
+ * Run migrations v3.
  */
-pub fn bootstrap() {
-    info!("Bootstrapping database");
-    let conn_res = Conn::new(build_opts());
-    if let Err(ref x) = conn_res {
-        error!("Could not connect to database ({:#?})...", x);
-        process::exit(1);
+fn run_migrations_v3(tx: &mut Transaction, version: i64) {
+    if version < 3 {
+        warn!("Running migrations to v3");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `priority` int NOT NULL DEFAULT 1;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v3...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "CREATE TABLE `rss-watcher-priority-rules` ( \
+                  `id` int NOT NULL AUTO_INCREMENT, \
+                  `feed_id` int, \
+                  `field` VARCHAR(255) NOT NULL DEFAULT 'entry.title', \
+                  `pattern` VARCHAR(255) NOT NULL, \
+                  `priority` int NOT NULL, \
+                  PRIMARY KEY (`id`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v3...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=3 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v3...! ({:#?}", x);
+            process::exit(1);
+        }
     }
-    let mut conn = conn_res.unwrap();
-    info!("Connected to database");
+}
 
-    if !table_exists(&mut conn) {
-        table_create(&mut conn);
+/**
+ * Run migrations v4.
+ */
+fn run_migrations_v4(tx: &mut Transaction, version: i64) {
+    if version < 4 {
+        warn!("Running migrations to v4");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `timezone` VARCHAR(64) NOT NULL DEFAULT 'UTC', \
+             ADD COLUMN `quiet_start` VARCHAR(5), \
+             ADD COLUMN `quiet_end` VARCHAR(5), \
+             ADD COLUMN `quiet_digest` BOOL NOT NULL DEFAULT 0, \
+             ADD COLUMN `quiet_override` int;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v4...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "CREATE TABLE `rss-watcher-queue` ( \
+                  `id` int NOT NULL AUTO_INCREMENT, \
+                  `feed_id` int NOT NULL, \
+                  `title` TEXT NOT NULL, \
+                  `message` TEXT NOT NULL, \
+                  `link` TEXT, \
+                  `priority` int NOT NULL, \
+                  `queued_at` int NOT NULL, \
+                  PRIMARY KEY (`id`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v4...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=4 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v4...! ({:#?}", x);
+            process::exit(1);
+        }
     }
+}
 
-    let version = get_db_version(&mut conn);
-    if version < 2 {
-        let res_tx = conn.start_transaction(TxOpts::default());
-        if let Err(x) = res_tx {
-            error!(
-                "Could not create transaction for updating last fetch time! {:#?}",
-                x
-            );
-            return;
+/**
+ * Run migrations v5.
+ */
+fn run_migrations_v5(tx: &mut Transaction, version: i64) {
+    if version < 5 {
+        warn!("Running migrations to v5");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `rate_limit_cycle` int, \
+             ADD COLUMN `rate_limit_hour` int;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v5...! ({:#?}", x);
+            process::exit(1);
         }
-        let mut tx = res_tx.unwrap();
 
-        run_migrations_v2(&mut tx, version);
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=5 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v5...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
 
-        if let Err(x) = tx.commit() {
-            warn!("Could not commit update! ({:#?}", x);
+/**
+ * Run migrations v6.
+ */
+fn run_migrations_v6(tx: &mut Transaction, version: i64) {
+    if version < 6 {
+        warn!("Running migrations to v6");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `backfill` VARCHAR(64);";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v6...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=6 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v6...! ({:#?}", x);
+            process::exit(1);
         }
-    } else {
-        info!("Database is up to date, no migrations to run.");
     }
+}
 
-    info!("Database should now be bootstrapped");
-    info!("We are assuming that the table has the correct columns");
-    info!("If not, we are going to get sql errors");
+/**
+ * Run migrations v7.
+ */
+fn run_migrations_v7(tx: &mut Transaction, version: i64) {
+    if version < 7 {
+        warn!("Running migrations to v7");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `notify_updates` BOOL NOT NULL DEFAULT 0, \
+             ADD COLUMN `update_title` VARCHAR(255) NOT NULL DEFAULT 'Updated: {{entry.title}}', \
+             ADD COLUMN `update_message` VARCHAR(255) NOT NULL DEFAULT '{{entry.summary}}';";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v7...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "CREATE TABLE `rss-watcher-entries` ( \
+                  `feed_id` int NOT NULL, \
+                  `entry_id` VARCHAR(255) NOT NULL, \
+                  `hash` CHAR(64) NOT NULL, \
+                  `updated` BIGINT, \
+                  PRIMARY KEY (`feed_id`, `entry_id`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v7...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=7 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v7...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
 }
 
 /**
- * This will fetch all feeds from the database and return them as a Vector.
+ * Run migrations v8.
  */
-pub fn get_feeds(conn: &mut Conn) -> Option<Vec<FeedConf>> {
-    let q = "SELECT `id`, \
-                    `url`, \
-                    `last_fetch`, \
-                    `title`, \
-                    `message`, \
-                    `push_url`, \
-                    `push_token` \
-               FROM `rss-watcher-feeds` \
-              WHERE `url` NOT LIKE 'version'";
-    let res = conn.query_map(
-        q,
-        |(id, url, last_fetch, title, message, push_url, push_token)| FeedConf {
-            id,
-            url,
-            last_fetch,
-            title,
-            message,
-            push_url,
-            push_token,
-        },
-    );
-    debug!("{:#?}", res);
-    match res {
-        Ok(r) => return Some(r),
-        Err(e) => {
-            error!("Could not get feeds from database ({:?})", e);
-            return None;
+fn run_migrations_v8(tx: &mut Transaction, version: i64) {
+    if version < 8 {
+        warn!("Running migrations to v8");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `missing_value` VARCHAR(255) NOT NULL DEFAULT '', \
+             ADD COLUMN `last_error` TEXT, \
+             ADD COLUMN `last_error_at` BIGINT;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v8...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=8 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v8...! ({:#?}", x);
+            process::exit(1);
         }
     }
 }
 
 /**
- * Method that updates the last fetch time timestamp in the database
+ * Run migrations v9.
  */
-pub fn update_last_fetch(feed_id: u32, last_fetch: i64, conn: &mut Conn) {
-    let res_tx = conn.start_transaction(TxOpts::default());
-    if let Err(x) = res_tx {
-        error!(
-            "Could not create transaction for updating last fetch time! {:#?}",
-            x
-        );
-        return;
+fn run_migrations_v9(tx: &mut Transaction, version: i64) {
+    if version < 9 {
+        warn!("Running migrations to v9");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `date_format` VARCHAR(64);";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v9...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=9 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v9...! ({:#?}", x);
+            process::exit(1);
+        }
     }
-    let mut tx = res_tx.unwrap();
+}
 
-    let q = "UPDATE `rss-watcher-feeds` SET last_fetch=?  WHERE id=?";
-    if let Err(x) = tx.exec_drop(q, (last_fetch, feed_id)) {
-        warn!("Could not update last fetch time...! ({:#?}", x);
+/**
+ * Run migrations v10.
+ */
+fn run_migrations_v10(tx: &mut Transaction, version: i64) {
+    if version < 10 {
+        warn!("Running migrations to v10");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `format` VARCHAR(16) NOT NULL DEFAULT 'markdown';";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v10...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=10 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v10...! ({:#?}", x);
+            process::exit(1);
+        }
     }
-    if let Err(x) = tx.commit() {
-        warn!("Could not commit update! ({:#?}", x);
+}
+
+/**
+ * Run migrations v11.
+ */
+fn run_migrations_v11(tx: &mut Transaction, version: i64) {
+    if version < 11 {
+        warn!("Running migrations to v11");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `max_length` INT UNSIGNED;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v11...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=11 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v11...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v12.
+ */
+fn run_migrations_v12(tx: &mut Transaction, version: i64) {
+    if version < 12 {
+        warn!("Running migrations to v12");
+        let mut q;
+        q = "CREATE TABLE `rss-watcher-templates` ( \
+                  `id` int NOT NULL AUTO_INCREMENT, \
+                  `name` VARCHAR(64) NOT NULL, \
+                  `title` VARCHAR(255), \
+                  `message` VARCHAR(255), \
+                  `update_title` VARCHAR(255), \
+                  `update_message` VARCHAR(255), \
+                  PRIMARY KEY (`id`), \
+                  UNIQUE KEY (`name`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v12...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        // The templates of a feed override the named template, so new feeds
+        // should not get their own by default.
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `template` VARCHAR(64), \
+             CHANGE COLUMN `title` `title` VARCHAR(255) NULL DEFAULT NULL, \
+             CHANGE COLUMN `message` `message` VARCHAR(255) NULL DEFAULT NULL, \
+             CHANGE COLUMN `update_title` `update_title` VARCHAR(255) NULL DEFAULT NULL, \
+             CHANGE COLUMN `update_message` `update_message` VARCHAR(255) NULL DEFAULT NULL;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v12...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        // Existing feeds got the templates as defaults in v2 and v7, those
+        // would now override the named templates. Only keep the ones that
+        // were changed.
+        q = "UPDATE `rss-watcher-feeds` \
+                SET `title` = NULLIF(`title`, '{{title}}: {{entry.title}}'), \
+                    `message` = NULLIF(`message`, '{{entry.summary}}'), \
+                    `update_title` = NULLIF(`update_title`, 'Updated: {{entry.title}}'), \
+                    `update_message` = NULLIF(`update_message`, '{{entry.summary}}') \
+              WHERE `url` NOT LIKE 'version';";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v12...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=12 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v12...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v13.
+ */
+fn run_migrations_v13(tx: &mut Transaction, version: i64) {
+    if version < 13 {
+        warn!("Running migrations to v13");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `big_image` BOOL NOT NULL DEFAULT 1, \
+             ADD COLUMN `feed_icon` BOOL NOT NULL DEFAULT 0, \
+             ADD COLUMN `extras` TEXT;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v13...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "ALTER TABLE `rss-watcher-queue` \
+             ADD COLUMN `image` TEXT;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v13...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=13 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v13...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v14.
+ */
+fn run_migrations_v14(tx: &mut Transaction, version: i64) {
+    if version < 14 {
+        warn!("Running migrations to v14");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `strip_images` BOOL NOT NULL DEFAULT 0, \
+             ADD COLUMN `strip_iframes` BOOL NOT NULL DEFAULT 1, \
+             ADD COLUMN `collapse_whitespace` BOOL NOT NULL DEFAULT 1;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v14...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=14 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v14...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v15.
+ */
+fn run_migrations_v15(tx: &mut Transaction, version: i64) {
+    if version < 15 {
+        warn!("Running migrations to v15");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `enabled` BOOL NOT NULL DEFAULT 1;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v15...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=15 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v15...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v16.
+ */
+fn run_migrations_v16(tx: &mut Transaction, version: i64) {
+    if version < 16 {
+        warn!("Running migrations to v16");
+        let mut q;
+        q = "CREATE TABLE `rss-watcher-targets` ( \
+                  `id` int NOT NULL AUTO_INCREMENT, \
+                  `name` VARCHAR(64) NOT NULL, \
+                  `push_url` VARCHAR(255) NOT NULL, \
+                  `push_token` VARCHAR(255) NOT NULL, \
+                  PRIMARY KEY (`id`), \
+                  UNIQUE KEY (`name`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v16...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "CREATE TABLE `rss-watcher-deliveries` ( \
+                  `id` int NOT NULL AUTO_INCREMENT, \
+                  `feed_id` int NOT NULL, \
+                  `title` TEXT NOT NULL, \
+                  `priority` int NOT NULL, \
+                  `status` VARCHAR(16) NOT NULL, \
+                  `error` TEXT, \
+                  `time` BIGINT NOT NULL, \
+                  PRIMARY KEY (`id`), \
+                  KEY (`feed_id`, `time`)
+             )";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v16...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        // Feeds that send to a target do not need their own push url/token
+        q = "ALTER TABLE `rss-watcher-feeds` \
+             ADD COLUMN `target_id` int, \
+             CHANGE COLUMN `push_url` `push_url` VARCHAR(255) NULL DEFAULT NULL, \
+             CHANGE COLUMN `push_token` `push_token` VARCHAR(255) NULL DEFAULT NULL;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v16...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=16 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v16...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v17.
+ */
+fn run_migrations_v17(tx: &mut Transaction, version: i64) {
+    if version < 17 {
+        warn!("Running migrations to v17");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-targets` \
+             ADD COLUMN `quiet_start` VARCHAR(5), \
+             ADD COLUMN `quiet_end` VARCHAR(5);";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v17...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=17 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v17...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Run migrations v18.
+ */
+fn run_migrations_v18(tx: &mut Transaction, version: i64) {
+    if version < 18 {
+        warn!("Running migrations to v18");
+        let mut q;
+        q = "ALTER TABLE `rss-watcher-targets` \
+             ADD COLUMN `rate_limit_minute` INT UNSIGNED;";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v18...! ({:#?}", x);
+            process::exit(1);
+        }
+
+        q = "UPDATE `rss-watcher-feeds` SET `last_fetch`=18 WHERE (`id`=0 OR `id`=1) AND `url` LIKE 'version' ";
+        if let Err(x) = tx.query_drop(q) {
+            error!("Could not run database migration to v18...! ({:#?}", x);
+            process::exit(1);
+        }
+    }
+}
+
+/**
+ * Bootstrap the database, this will make sure tables exists,
+ * create them if not and run migrations if nececarry.
+ */
+pub fn bootstrap() {
+    info!("Bootstrapping database");
+    let conn_res = Conn::new(build_opts());
+    if let Err(ref x) = conn_res {
+        error!("Could not connect to database ({:#?})...", x);
+        process::exit(1);
+    }
+    let mut conn = conn_res.unwrap();
+    info!("Connected to database");
+
+    if dryrun::enabled() {
+        if !table_exists(&mut conn) || get_db_version(&mut conn) < DB_VERSION {
+            error!("The database has to be created or migrated, which a dry run can not do");
+            process::exit(1);
+        }
+        info!("Dry run, not touching the database");
+        return;
+    }
+
+    if !table_exists(&mut conn) {
+        table_create(&mut conn);
+    }
+
+    let version = get_db_version(&mut conn);
+    if version < DB_VERSION {
+        let res_tx = conn.start_transaction(TxOpts::default());
+        if let Err(x) = res_tx {
+            error!(
+                "Could not create transaction for updating last fetch time! {:#?}",
+                x
+            );
+            return;
+        }
+        let mut tx = res_tx.unwrap();
+
+        run_migrations_v2(&mut tx, version);
+        run_migrations_v3(&mut tx, version);
+        run_migrations_v4(&mut tx, version);
+        run_migrations_v5(&mut tx, version);
+        run_migrations_v6(&mut tx, version);
+        run_migrations_v7(&mut tx, version);
+        run_migrations_v8(&mut tx, version);
+        run_migrations_v9(&mut tx, version);
+        run_migrations_v10(&mut tx, version);
+        run_migrations_v11(&mut tx, version);
+        run_migrations_v12(&mut tx, version);
+        run_migrations_v13(&mut tx, version);
+        run_migrations_v14(&mut tx, version);
+        run_migrations_v15(&mut tx, version);
+        run_migrations_v16(&mut tx, version);
+        run_migrations_v17(&mut tx, version);
+        run_migrations_v18(&mut tx, version);
+
+        if let Err(x) = tx.commit() {
+            warn!("Could not commit update! ({:#?}", x);
+        }
+    } else {
+        info!("Database is up to date, no migrations to run.");
+    }
+
+    info!("Database should now be bootstrapped");
+    info!("We are assuming that the table has the correct columns");
+    info!("If not, we are going to get sql errors");
+}
+
+/**
+ * This will fetch all feeds from the database and return them as a Vector.
+ */
+pub fn get_feeds(conn: &mut Conn) -> Option<Vec<FeedConf>> {
+    // The templates of a feed come from its own columns, then the template it
+    // references, then the template named `default` and at last the built in
+    // defaults. The push url and token come from the target if it has one,
+    // the quiet hours only if the feed has none of its own.
+    let q = "SELECT f.`id`, \
+                    f.`url`, \
+                    f.`enabled`, \
+                    f.`last_fetch`, \
+                    f.`template`, \
+                    t.`name` AS `template_found`, \
+                    COALESCE(f.`title`, t.`title`, d.`title`, :title) AS `title`, \
+                    COALESCE(f.`message`, t.`message`, d.`message`, :message) AS `message`, \
+                    f.`target_id`, \
+                    COALESCE(g.`push_url`, f.`push_url`, '') AS `push_url`, \
+                    COALESCE(g.`push_token`, f.`push_token`, '') AS `push_token`, \
+                    f.`priority`, \
+                    f.`timezone`, \
+                    IF(f.`quiet_start` IS NULL AND f.`quiet_end` IS NULL, \
+                        g.`quiet_start`, f.`quiet_start`) AS `quiet_start`, \
+                    IF(f.`quiet_start` IS NULL AND f.`quiet_end` IS NULL, \
+                        g.`quiet_end`, f.`quiet_end`) AS `quiet_end`, \
+                    f.`quiet_digest`, \
+                    f.`quiet_override`, \
+                    f.`rate_limit_cycle`, \
+                    f.`rate_limit_hour`, \
+                    g.`rate_limit_minute` AS `target_rate_limit`, \
+                    f.`backfill`, \
+                    f.`notify_updates`, \
+                    COALESCE(f.`update_title`, t.`update_title`, d.`update_title`, :update_title) \
+                        AS `update_title`, \
+                    COALESCE(f.`update_message`, t.`update_message`, d.`update_message`, \
+                        :update_message) AS `update_message`, \
+                    f.`missing_value`, \
+                    f.`date_format`, \
+                    f.`format`, \
+                    f.`max_length`, \
+                    f.`big_image`, \
+                    f.`feed_icon`, \
+                    f.`extras`, \
+                    f.`strip_images`, \
+                    f.`strip_iframes`, \
+                    f.`collapse_whitespace`, \
+                    f.`last_error`, \
+                    f.`last_error_at` \
+               FROM `rss-watcher-feeds` f \
+          LEFT JOIN `rss-watcher-templates` t ON t.`name` = f.`template` \
+          LEFT JOIN `rss-watcher-templates` d ON d.`name` = 'default' \
+          LEFT JOIN `rss-watcher-targets` g ON g.`id` = f.`target_id` \
+              WHERE f.`url` NOT LIKE 'version'";
+    let defaults = FeedConf::default();
+    let p = params! {
+        "title" => &defaults.title,
+        "message" => &defaults.message,
+        "update_title" => &defaults.update_title,
+        "update_message" => &defaults.update_message,
+    };
+    let res = conn.exec_map(q, p, |mut row: Row| {
+        let id = row.take("id").unwrap();
+        let mut errors = Vec::new();
+        let timezone: String = row.take("timezone").unwrap();
+        let tz = parse_timezone(&timezone, &mut errors);
+        let template: Option<String> = row.take("template").unwrap();
+        let template_found: Option<String> = row.take("template_found").unwrap();
+        if template.is_some() && template_found.is_none() {
+            warn!(
+                "Feed {} uses the template {:?}, which does not exist",
+                id, template
+            );
+        }
+        let mut feed = FeedConf {
+            id,
+            url: row.take("url").unwrap(),
+            enabled: row.take("enabled").unwrap(),
+            last_fetch: row.take("last_fetch").unwrap(),
+            template,
+            title: row.take("title").unwrap(),
+            message: row.take("message").unwrap(),
+            target: parse_unsigned(row.take("target_id").unwrap(), "target", &mut errors),
+            push_url: row.take("push_url").unwrap(),
+            push_token: row.take("push_token").unwrap(),
+            priority: parse_priority(row.take("priority").unwrap(), "priority", &mut errors),
+            priority_rules: Vec::new(),
+            timezone,
+            tz,
+            quiet_start: row.take("quiet_start").unwrap(),
+            quiet_end: row.take("quiet_end").unwrap(),
+            quiet_digest: row.take("quiet_digest").unwrap(),
+            quiet_override: parse_unsigned(
+                row.take("quiet_override").unwrap(),
+                "quiet hours override",
+                &mut errors,
+            ),
+            rate_limit_cycle: parse_unsigned(
+                row.take("rate_limit_cycle").unwrap(),
+                "rate limit per cycle",
+                &mut errors,
+            ),
+            rate_limit_hour: parse_unsigned(
+                row.take("rate_limit_hour").unwrap(),
+                "rate limit per hour",
+                &mut errors,
+            ),
+            target_rate_limit: row.take("target_rate_limit").unwrap(),
+            backfill: parse_backfill(id, row.take("backfill").unwrap()),
+            notify_updates: row.take("notify_updates").unwrap(),
+            update_title: row.take("update_title").unwrap(),
+            update_message: row.take("update_message").unwrap(),
+            missing_value: row.take("missing_value").unwrap(),
+            date_format: row.take("date_format").unwrap(),
+            format: parse_format(id, row.take("format").unwrap()),
+            max_length: row.take("max_length").unwrap(),
+            big_image: row.take("big_image").unwrap(),
+            feed_icon: row.take("feed_icon").unwrap(),
+            extras: parse_extras(id, row.take("extras").unwrap()),
+            strip_images: row.take("strip_images").unwrap(),
+            strip_iframes: row.take("strip_iframes").unwrap(),
+            collapse_whitespace: row.take("collapse_whitespace").unwrap(),
+            last_error: row.take("last_error").unwrap(),
+            last_error_at: row.take("last_error_at").unwrap(),
+            config_error: None,
+        };
+        if !errors.is_empty() {
+            feed.config_error = Some(errors.join(", "));
+        }
+        feed
+    });
+    debug!("{:#?}", res);
+    let mut feeds;
+    match res {
+        Ok(r) => feeds = r,
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get feeds from database ({:?})", e);
+            return None;
+        }
+    }
+
+    let rules = get_priority_rules(conn)?;
+    for (rule, errors) in &rules {
+        for feed in feeds.iter_mut() {
+            if rule.feed_id.is_none() || rule.feed_id == Some(feed.id) {
+                feed.priority_rules.push(rule.clone());
+                for e in errors {
+                    let error = match &feed.config_error {
+                        Some(x) => format!("{}, {}", x, e),
+                        None => e.to_owned(),
+                    };
+                    feed.config_error = Some(error);
+                }
+            }
+        }
+    }
+    return Some(feeds);
+}
+
+/**
+ * This will fetch all priority rules from the database, both the global ones
+ * (where feed_id is NULL) and the ones for specific feeds. Each rule comes
+ * with the problems found in it, like a field that does not exist, which
+ * would never match.
+ */
+fn get_priority_rules(conn: &mut Conn) -> Option<Vec<(PriorityRule, Vec<String>)>> {
+    let q = "SELECT `feed_id`, \
+                    `field`, \
+                    `pattern`, \
+                    `priority` \
+               FROM `rss-watcher-priority-rules`";
+    let res = conn.query_map(q, |(feed_id, field, pattern, priority)| {
+        let mut errors = Vec::new();
+        let field: String = field;
+        let pattern: String = pattern;
+        let what = format!("priority of priority rule {:?}", pattern);
+        let priority = parse_priority(priority, &what, &mut errors);
+        if let Err(e) = rss_utils::validate_template(&format!("{{{{{}}}}}", field)) {
+            errors.push(format!(
+                "Invalid field of priority rule {:?} ({})",
+                pattern, e
+            ));
+        }
+        let rule = PriorityRule {
+            feed_id,
+            field,
+            pattern,
+            priority,
+        };
+        (rule, errors)
+    });
+    debug!("{:#?}", res);
+    match res {
+        Ok(r) => return Some(r),
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get priority rules from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Method that updates the last fetch time timestamp in the database
+ */
+pub fn update_last_fetch(feed_id: u32, last_fetch: i64, conn: &mut Conn) {
+    if dryrun::enabled() {
+        debug!("Dry run, not updating last fetch time of feed {}", feed_id);
+        return;
+    }
+    let res_tx = conn.start_transaction(TxOpts::default());
+    if let Err(x) = res_tx {
+        metrics::db_error();
+        error!(
+            "Could not create transaction for updating last fetch time! {:#?}",
+            x
+        );
+        return;
+    }
+    let mut tx = res_tx.unwrap();
+
+    let q = "UPDATE `rss-watcher-feeds` SET last_fetch=?  WHERE id=?";
+    if let Err(x) = tx.exec_drop(q, (last_fetch, feed_id)) {
+        metrics::db_error();
+        warn!("Could not update last fetch time...! ({:#?}", x);
+    }
+    if let Err(x) = tx.commit() {
+        metrics::db_error();
+        warn!("Could not commit update! ({:#?}", x);
+    }
+}
+
+/**
+ * Put a notification in the queue for a feed, it will be sent when the quiet
+ * hours of the feed are over.
+ */
+pub fn queue_notification(
+    feed_id: u32,
+    notification: &Notification,
+    queued_at: i64,
+    conn: &mut Conn,
+) -> bool {
+    if dryrun::enabled() {
+        info!(
+            "Dry run, not queueing notification with title \"{}\"",
+            notification.title
+        );
+        return true;
+    }
+    let q = "INSERT INTO `rss-watcher-queue` (feed_id, title, message, link, image, priority, queued_at) \
+                  VALUES (?, ?, ?, ?, ?, ?, ?)";
+    let res = conn.exec_drop(
+        q,
+        (
+            feed_id,
+            &notification.title,
+            &notification.message,
+            &notification.link,
+            &notification.image,
+            notification.priority,
+            queued_at,
+        ),
+    );
+    if let Err(x) = res {
+        metrics::db_error();
+        error!("Could not queue notification! ({:#?})", x);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Get all queued notifications for a feed, oldest first. Returns the id of
+ * the queue row together with the notification.
+ */
+pub fn get_queued_notifications(feed_id: u32, conn: &mut Conn) -> Option<Vec<(u32, Notification)>> {
+    let q = "SELECT `id`, `title`, `message`, `link`, `image`, `priority` \
+               FROM `rss-watcher-queue` \
+              WHERE `feed_id`=? \
+           ORDER BY `queued_at`, `id`";
+    let res = conn.exec_map(
+        q,
+        (feed_id,),
+        |(id, title, message, link, image, priority)| {
+            (
+                id,
+                Notification {
+                    title,
+                    message,
+                    link,
+                    image,
+                    priority,
+                },
+            )
+        },
+    );
+    match res {
+        Ok(r) => return Some(r),
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get queued notifications from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Remove a notification from the queue, typically after it has been sent.
+ */
+pub fn delete_queued_notification(queue_id: u32, conn: &mut Conn) {
+    if dryrun::enabled() {
+        debug!("Dry run, not removing notification {} from queue", queue_id);
+        return;
+    }
+    let q = "DELETE FROM `rss-watcher-queue` WHERE id=?";
+    if let Err(x) = conn.exec_drop(q, (queue_id,)) {
+        metrics::db_error();
+        warn!("Could not remove notification from queue...! ({:#?}", x);
+    }
+}
+
+/**
+ * Get the content hash and updated timestamp we stored the last time we saw
+ * an entry, or None if we have not seen it before.
+ */
+pub fn get_seen_entry(
+    feed_id: u32,
+    entry_id: &str,
+    conn: &mut Conn,
+) -> Option<(String, Option<i64>)> {
+    let q = "SELECT `hash`, `updated` \
+               FROM `rss-watcher-entries` \
+              WHERE `feed_id`=? AND `entry_id`=?";
+    let res: Result<Option<(String, Option<i64>)>> = conn.exec_first(q, (feed_id, entry_id));
+    match res {
+        Ok(r) => return r,
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get seen entry from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Store the content hash and updated timestamp of an entry.
+ */
+pub fn save_seen_entry(
+    feed_id: u32,
+    entry_id: &str,
+    hash: &str,
+    updated: Option<i64>,
+    conn: &mut Conn,
+) {
+    if dryrun::enabled() {
+        debug!("Dry run, not saving seen entry {:?}", entry_id);
+        return;
+    }
+    let q = "INSERT INTO `rss-watcher-entries` (feed_id, entry_id, hash, updated) \
+                  VALUES (?, ?, ?, ?) \
+                      ON DUPLICATE KEY UPDATE hash=VALUES(hash), updated=VALUES(updated)";
+    if let Err(x) = conn.exec_drop(q, (feed_id, entry_id, hash, updated)) {
+        metrics::db_error();
+        warn!("Could not save seen entry...! ({:#?}", x);
+    }
+}
+
+/**
+ * Record the health of a feed, `error` is None if the last check of the feed
+ * went well. The error is kept in the `last_error` column until the feed is
+ * checked successfully again.
+ */
+pub fn set_feed_error(feed_id: u32, error: Option<&str>, time: i64, conn: &mut Conn) {
+    if dryrun::enabled() {
+        debug!("Dry run, not updating error of feed {}", feed_id);
+        return;
+    }
+    let q = "UPDATE `rss-watcher-feeds` SET last_error=?, last_error_at=? WHERE id=?";
+    let error_at = error.map(|_| time);
+    if let Err(x) = conn.exec_drop(q, (error, error_at, feed_id)) {
+        metrics::db_error();
+        warn!("Could not update feed error...! ({:#?}", x);
+    }
+}
+
+/**
+ * Insert a row with the given column values, returns the id of the new row.
+ * The table and column names are not escaped, so they must not come from
+ * user input.
+ */
+fn insert_row(table: &str, values: &[(&str, Value)], conn: &mut Conn) -> Option<u32> {
+    if dryrun::enabled() {
+        info!("Dry run, not adding to {}", table);
+        return None;
+    }
+    let columns: Vec<String> = values.iter().map(|(x, _)| format!("`{}`", x)).collect();
+    let placeholders: Vec<&str> = values.iter().map(|_| "?").collect();
+    let q = format!(
+        "INSERT INTO `{}` ({}) VALUES ({})",
+        table,
+        columns.join(", "),
+        placeholders.join(", ")
+    );
+    let params: Vec<Value> = values.iter().map(|(_, x)| x.to_owned()).collect();
+    if let Err(x) = conn.exec_drop(q, params) {
+        metrics::db_error();
+        error!("Could not add to {}! ({:#?})", table, x);
+        return None;
+    }
+    return Some(conn.last_insert_id() as u32);
+}
+
+/**
+ * Set the given columns of a row, returns false if it failed or there is no
+ * row with that id. The table and column names are not escaped, so they must
+ * not come from user input.
+ */
+fn update_row(table: &str, id: u32, values: &[(&str, Value)], conn: &mut Conn) -> bool {
+    let q = format!("SELECT COUNT(*) FROM `{}` WHERE id=?", table);
+    match conn.exec_first::<u32, _, _>(q, (id,)) {
+        Ok(Some(x)) if x > 0 => {}
+        Ok(_) => return false,
+        Err(x) => {
+            metrics::db_error();
+            error!("Could not look up {} in {}! ({:#?})", id, table, x);
+            return false;
+        }
+    }
+    if values.is_empty() {
+        return true;
+    }
+    if dryrun::enabled() {
+        info!("Dry run, not updating {} in {}", id, table);
+        return true;
+    }
+    let columns: Vec<String> = values.iter().map(|(x, _)| format!("`{}`=?", x)).collect();
+    let q = format!("UPDATE `{}` SET {} WHERE id=?", table, columns.join(", "));
+    let mut params: Vec<Value> = values.iter().map(|(_, x)| x.to_owned()).collect();
+    params.push(id.into());
+    if let Err(x) = conn.exec_drop(q, params) {
+        metrics::db_error();
+        error!("Could not update {} in {}! ({:#?})", id, table, x);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Delete a row, returns false if it failed or there is no row with that id.
+ */
+fn delete_row(table: &str, id: u32, conn: &mut Conn) -> bool {
+    if dryrun::enabled() {
+        info!("Dry run, not removing {} from {}", id, table);
+        return true;
+    }
+    let q = format!("DELETE FROM `{}` WHERE id=?", table);
+    if let Err(x) = conn.exec_drop(q, (id,)) {
+        metrics::db_error();
+        error!("Could not remove {} from {}! ({:#?})", id, table, x);
+        return false;
+    }
+    return conn.affected_rows() > 0;
+}
+
+/**
+ * Add a feed with the given column values, returns the id of the new feed.
+ * The column names are not escaped, so they must not come from user input.
+ */
+pub fn insert_feed(values: &[(&str, Value)], conn: &mut Conn) -> Option<u32> {
+    return insert_row("rss-watcher-feeds", values, conn);
+}
+
+/**
+ * Set the given columns of a feed, returns false if it failed or there is no
+ * feed with that id. The column names are not escaped, so they must not come
+ * from user input.
+ */
+pub fn update_feed(feed_id: u32, values: &[(&str, Value)], conn: &mut Conn) -> bool {
+    if dryrun::enabled() {
+        info!("Dry run, not updating feed {}", feed_id);
+        return feed_exists(feed_id, conn);
+    }
+    if !feed_exists(feed_id, conn) {
+        return false;
+    }
+    if values.is_empty() {
+        return true;
+    }
+    let columns: Vec<String> = values.iter().map(|(x, _)| format!("`{}`=?", x)).collect();
+    let q = format!(
+        "UPDATE `rss-watcher-feeds` SET {} WHERE id=? AND `url` NOT LIKE 'version'",
+        columns.join(", ")
+    );
+    let mut params: Vec<Value> = values.iter().map(|(_, x)| x.to_owned()).collect();
+    params.push(feed_id.into());
+    if let Err(x) = conn.exec_drop(q, params) {
+        metrics::db_error();
+        error!("Could not update feed {}! ({:#?})", feed_id, x);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Check wether there is a feed with the given id.
+ */
+fn feed_exists(feed_id: u32, conn: &mut Conn) -> bool {
+    let q = "SELECT COUNT(*) FROM `rss-watcher-feeds` WHERE id=? AND `url` NOT LIKE 'version'";
+    match conn.exec_first::<u32, _, _>(q, (feed_id,)) {
+        Ok(x) => return x.unwrap_or(0) > 0,
+        Err(x) => {
+            metrics::db_error();
+            error!("Could not look up feed {}! ({:#?})", feed_id, x);
+            return false;
+        }
+    }
+}
+
+/**
+ * Remove a feed, together with its priority rules, queued notifications, seen
+ * entries and deliveries. Returns false if it failed or there is no feed with
+ * that id.
+ */
+pub fn delete_feed(feed_id: u32, conn: &mut Conn) -> bool {
+    if dryrun::enabled() {
+        info!("Dry run, not removing feed {}", feed_id);
+        return feed_exists(feed_id, conn);
+    }
+    let res_tx = conn.start_transaction(TxOpts::default());
+    if let Err(x) = res_tx {
+        metrics::db_error();
+        error!("Could not create transaction for removing feed! {:#?}", x);
+        return false;
+    }
+    let mut tx = res_tx.unwrap();
+
+    let q = "DELETE FROM `rss-watcher-feeds` WHERE id=? AND `url` NOT LIKE 'version'";
+    if let Err(x) = tx.exec_drop(q, (feed_id,)) {
+        metrics::db_error();
+        error!("Could not remove feed {}! ({:#?})", feed_id, x);
+        return false;
+    }
+    if tx.affected_rows() == 0 {
+        return false;
+    }
+    let queries = [
+        "DELETE FROM `rss-watcher-priority-rules` WHERE feed_id=?",
+        "DELETE FROM `rss-watcher-queue` WHERE feed_id=?",
+        "DELETE FROM `rss-watcher-entries` WHERE feed_id=?",
+        "DELETE FROM `rss-watcher-deliveries` WHERE feed_id=?",
+    ];
+    for q in queries {
+        if let Err(x) = tx.exec_drop(q, (feed_id,)) {
+            metrics::db_error();
+            error!("Could not remove feed {}! ({:#?})", feed_id, x);
+            return false;
+        }
+    }
+    if let Err(x) = tx.commit() {
+        metrics::db_error();
+        error!("Could not commit removing feed! ({:#?})", x);
+        return false;
+    }
+    return true;
+}
+
+/**
+ * Get all shared templates.
+ */
+pub fn get_templates(conn: &mut Conn) -> Option<Vec<Template>> {
+    let q = "SELECT `id`, `name`, `title`, `message`, `update_title`, `update_message` \
+               FROM `rss-watcher-templates` \
+           ORDER BY `name`";
+    let res = conn.query_map(
+        q,
+        |(id, name, title, message, update_title, update_message)| Template {
+            id,
+            name,
+            title,
+            message,
+            update_title,
+            update_message,
+        },
+    );
+    match res {
+        Ok(r) => return Some(r),
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get templates from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Add a shared template, returns its id.
+ */
+pub fn insert_template(values: &[(&str, Value)], conn: &mut Conn) -> Option<u32> {
+    return insert_row("rss-watcher-templates", values, conn);
+}
+
+/**
+ * Change a shared template, returns false if there is no such template.
+ */
+pub fn update_template(template_id: u32, values: &[(&str, Value)], conn: &mut Conn) -> bool {
+    return update_row("rss-watcher-templates", template_id, values, conn);
+}
+
+/**
+ * Remove a shared template, feeds that use it fall back to the default.
+ */
+pub fn delete_template(template_id: u32, conn: &mut Conn) -> bool {
+    return delete_row("rss-watcher-templates", template_id, conn);
+}
+
+/**
+ * Get all notification targets.
+ */
+pub fn get_targets(conn: &mut Conn) -> Option<Vec<Target>> {
+    let q = "SELECT `id`, `name`, `push_url`, `push_token`, `quiet_start`, `quiet_end`, \
+                    `rate_limit_minute` \
+               FROM `rss-watcher-targets` \
+           ORDER BY `name`";
+    let res = conn.query_map(
+        q,
+        |(id, name, push_url, push_token, quiet_start, quiet_end, rate_limit_minute)| Target {
+            id,
+            name,
+            push_url,
+            push_token,
+            quiet_start,
+            quiet_end,
+            rate_limit_minute,
+        },
+    );
+    match res {
+        Ok(r) => return Some(r),
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get targets from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Add a notification target, returns its id.
+ */
+pub fn insert_target(values: &[(&str, Value)], conn: &mut Conn) -> Option<u32> {
+    return insert_row("rss-watcher-targets", values, conn);
+}
+
+/**
+ * Change a notification target, returns false if there is no such target.
+ */
+pub fn update_target(target_id: u32, values: &[(&str, Value)], conn: &mut Conn) -> bool {
+    return update_row("rss-watcher-targets", target_id, values, conn);
+}
+
+/**
+ * Remove a notification target.
+ */
+pub fn delete_target(target_id: u32, conn: &mut Conn) -> bool {
+    return delete_row("rss-watcher-targets", target_id, conn);
+}
+
+/**
+ * Count the feeds that send to a target.
+ */
+pub fn count_target_feeds(target_id: u32, conn: &mut Conn) -> Option<u32> {
+    let q = "SELECT COUNT(*) FROM `rss-watcher-feeds` WHERE target_id=?";
+    match conn.exec_first::<u32, _, _>(q, (target_id,)) {
+        Ok(x) => return Some(x.unwrap_or(0)),
+        Err(x) => {
+            metrics::db_error();
+            error!("Could not count feeds of target {}! ({:#?})", target_id, x);
+            return None;
+        }
+    }
+}
+
+/**
+ * Record that a notification was sent, failed to send or was queued.
+ */
+pub fn record_delivery(
+    feed_id: u32,
+    notification: &Notification,
+    status: &str,
+    error: Option<&str>,
+    time: i64,
+    conn: &mut Conn,
+) {
+    if dryrun::enabled() {
+        return;
+    }
+    let q = "INSERT INTO `rss-watcher-deliveries` (feed_id, title, priority, status, error, time) \
+                  VALUES (?, ?, ?, ?, ?, ?)";
+    let res = conn.exec_drop(
+        q,
+        (
+            feed_id,
+            &notification.title,
+            notification.priority,
+            status,
+            error,
+            time,
+        ),
+    );
+    if let Err(x) = res {
+        metrics::db_error();
+        warn!("Could not record delivery...! ({:#?}", x);
+    }
+}
+
+/**
+ * Get the latest deliveries, newest first, optionally only for one feed.
+ */
+pub fn get_deliveries(feed_id: Option<u32>, limit: u32, conn: &mut Conn) -> Option<Vec<Delivery>> {
+    let q = "SELECT `id`, `feed_id`, `title`, `priority`, `status`, `error`, `time` \
+               FROM `rss-watcher-deliveries` \
+              WHERE ? IS NULL OR `feed_id`=? \
+           ORDER BY `time` DESC, `id` DESC \
+              LIMIT ?";
+    let res = conn.exec_map(
+        q,
+        (feed_id, feed_id, limit),
+        |(id, feed_id, title, priority, status, error, time)| Delivery {
+            id,
+            feed_id,
+            title,
+            priority,
+            status,
+            error,
+            time,
+        },
+    );
+    match res {
+        Ok(r) => return Some(r),
+        Err(e) => {
+            metrics::db_error();
+            error!("Could not get deliveries from database ({:?})", e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Remove deliveries older than `before` from the history.
+ */
+pub fn prune_deliveries(before: i64, conn: &mut Conn) {
+    if dryrun::enabled() {
+        return;
+    }
+    let q = "DELETE FROM `rss-watcher-deliveries` WHERE time<?";
+    if let Err(x) = conn.exec_drop(q, (before,)) {
+        metrics::db_error();
+        warn!("Could not prune deliveries...! ({:#?}", x);
+    }
+}
+
+/**
+ * The name of the lock that the instance checking the feeds holds. Locks are
+ * server wide, so the name includes the database.
+ */
+pub fn lock_name() -> String {
+    let db_base = env::var("DB_BASE").expect("$DB_BASE is not set");
+    // MySQL lock names can be at most 64 characters
+    return format!("rss-watcher:{}", db_base)
+        .chars()
+        .take(64)
+        .collect();
+}
+
+/**
+ * Check wether this connection holds the lock, returns None if we could not
+ * check.
+ */
+pub fn holds_lock(name: &str, conn: &mut Conn) -> Option<bool> {
+    let q = "SELECT IS_USED_LOCK(?) = CONNECTION_ID()";
+    match conn.exec_first::<Option<i64>, _, _>(q, (name,)) {
+        Ok(x) => return Some(x.flatten() == Some(1)),
+        Err(x) => {
+            metrics::db_error();
+            warn!("Could not check lock {:?}! ({:#?})", name, x);
+            return None;
+        }
+    }
+}
+
+/**
+ * Try to take the lock without waiting, returns false if another connection
+ * holds it and None if we could not try. The lock is released when the
+ * connection is closed.
+ */
+pub fn try_lock(name: &str, conn: &mut Conn) -> Option<bool> {
+    let q = "SELECT GET_LOCK(?, 0)";
+    match conn.exec_first::<Option<i64>, _, _>(q, (name,)) {
+        Ok(Some(Some(x))) => return Some(x == 1),
+        Ok(_) => {
+            metrics::db_error();
+            warn!("Could not take lock {:?}!", name);
+            return None;
+        }
+        Err(x) => {
+            metrics::db_error();
+            warn!("Could not take lock {:?}! ({:#?})", name, x);
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn backfill(value: &str) -> Backfill {
+        return parse_backfill(1, Some(value.to_owned()));
+    }
+
+    #[test]
+    fn no_backfill() {
+        assert_eq!(parse_backfill(1, None), Backfill::None);
+        assert_eq!(backfill(""), Backfill::None);
+        assert_eq!(backfill("none"), Backfill::None);
+        assert_eq!(backfill(" NONE "), Backfill::None);
+    }
+
+    #[test]
+    fn backfill_latest() {
+        assert_eq!(backfill("latest:5"), Backfill::Latest(5));
+        assert_eq!(backfill("latest: 0"), Backfill::Latest(0));
+    }
+
+    #[test]
+    fn backfill_since() {
+        assert_eq!(
+            backfill("since:2024-01-02"),
+            Backfill::Since(Utc.with_ymd_and_hms(2024, 1, 2, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            backfill("since:2024-01-02T03:04:05+02:00"),
+            Backfill::Since(Utc.with_ymd_and_hms(2024, 1, 2, 1, 4, 5).unwrap())
+        );
+    }
+
+    #[test]
+    fn invalid_backfill() {
+        assert_eq!(backfill("latest"), Backfill::None);
+        assert_eq!(backfill("latest:-1"), Backfill::None);
+        assert_eq!(backfill("latest:many"), Backfill::None);
+        assert_eq!(backfill("since:yesterday"), Backfill::None);
+        assert_eq!(backfill("since:2024-13-01"), Backfill::None);
+        assert_eq!(backfill("everything"), Backfill::None);
+    }
+
+    #[test]
+    fn unsigned_columns() {
+        let mut errors = Vec::new();
+        assert_eq!(parse_unsigned(None, "target", &mut errors), None);
+        assert_eq!(parse_unsigned(Some(0), "target", &mut errors), Some(0));
+        assert_eq!(parse_unsigned(Some(3), "target", &mut errors), Some(3));
+        assert!(errors.is_empty());
+        assert_eq!(parse_unsigned(Some(-3), "target", &mut errors), None);
+        assert_eq!(errors, vec!["Invalid target (-3), ignoring it"]);
     }
 }
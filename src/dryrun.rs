@@ -0,0 +1,22 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/**
+ * Wether we are doing a dry run. In a dry run feeds are fetched and rendered
+ * as usual, but no notifications are sent and nothing is written to the
+ * database.
+ */
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/**
+ * Turn on dry run mode for the rest of the process.
+ */
+pub fn enable() {
+    DRY_RUN.store(true, Ordering::Relaxed);
+}
+
+/**
+ * Check wether this is a dry run.
+ */
+pub fn enabled() -> bool {
+    return DRY_RUN.load(Ordering::Relaxed);
+}
@@ -0,0 +1,437 @@
+use crate::database::{self, FeedConf, Format};
+use crate::dryrun;
+use crate::rss_utils;
+
+use chrono::prelude::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use clap::Args;
+use feed_rs::model::Feed;
+use log::error;
+use mysql::{Conn, Value};
+use serde::Deserialize;
+
+/// Feed settings that can be given on the command line, or as json to the
+/// admin API
+#[derive(Args, Default, Deserialize)]
+#[serde(default)]
+pub struct FeedOptions {
+    /// Id of the notification target to send to, instead of a push url and
+    /// token, 0 to clear it
+    #[arg(long)]
+    pub target: Option<u32>,
+    /// Root url of the gotify server, e.g. https://push.example.com
+    #[arg(long)]
+    pub push_url: Option<String>,
+    /// Token of the gotify app
+    #[arg(long)]
+    pub push_token: Option<String>,
+    /// Title template, empty to use the shared or default one again
+    #[arg(long)]
+    pub title: Option<String>,
+    /// Message template, empty to use the shared or default one again
+    #[arg(long)]
+    pub message: Option<String>,
+    /// Name of a shared template, an empty name to clear it
+    #[arg(long)]
+    pub template: Option<String>,
+    /// Default priority of the notifications
+    #[arg(long)]
+    pub priority: Option<u32>,
+    /// Output format, markdown, html or plain
+    #[arg(long)]
+    pub format: Option<String>,
+    /// Timezone used for dates and quiet hours, e.g. Europe/Oslo
+    #[arg(long)]
+    pub timezone: Option<String>,
+    /// Maximum length of the title and message, 0 to clear it
+    #[arg(long)]
+    pub max_length: Option<u32>,
+    /// Wether the feed is checked, the command line has enable and disable
+    /// for this
+    #[arg(skip)]
+    pub enabled: Option<bool>,
+}
+
+/**
+ * Tell the user what was done to a feed, or what would have been done in a
+ * dry run.
+ */
+fn report(feed_id: u32, done: &str) {
+    if dryrun::enabled() {
+        println!("Dry run, feed {} would have been {}", feed_id, done);
+    } else {
+        println!("Feed {} was {}", feed_id, done);
+    }
+}
+
+/**
+ * Make sure the database is set up and connect to it.
+ */
+fn connect() -> Option<Conn> {
+    database::bootstrap();
+    return database::new_conn();
+}
+
+/**
+ * Get the config of a single feed from the database.
+ */
+fn find_feed(feed_id: u32, conn: &mut Conn) -> Option<FeedConf> {
+    let feed = database::get_feeds(conn)?
+        .into_iter()
+        .find(|x| x.id == feed_id);
+    if feed.is_none() {
+        error!("There is no feed with id {}", feed_id);
+    }
+    return feed;
+}
+
+/**
+ * Check the options and turn them into column values for the database.
+ */
+fn column_values(options: &FeedOptions) -> Result<Vec<(&'static str, Value)>, String> {
+    let mut values: Vec<(&'static str, Value)> = Vec::new();
+    match options.target {
+        Some(0) => values.push(("target_id", Value::NULL)),
+        Some(x) => values.push(("target_id", x.into())),
+        None => {}
+    }
+    if let Some(x) = &options.push_url {
+        values.push(("push_url", x.into()));
+    }
+    if let Some(x) = &options.push_token {
+        values.push(("push_token", x.into()));
+    }
+    match &options.title {
+        Some(x) if x.is_empty() => values.push(("title", Value::NULL)),
+        Some(x) => {
+            rss_utils::validate_template(x)
+                .map_err(|e| format!("Invalid title template: {}", e))?;
+            values.push(("title", x.into()));
+        }
+        None => {}
+    }
+    match &options.message {
+        Some(x) if x.is_empty() => values.push(("message", Value::NULL)),
+        Some(x) => {
+            rss_utils::validate_template(x)
+                .map_err(|e| format!("Invalid message template: {}", e))?;
+            values.push(("message", x.into()));
+        }
+        None => {}
+    }
+    match &options.template {
+        Some(x) if x.is_empty() => values.push(("template", Value::NULL)),
+        Some(x) => values.push(("template", x.into())),
+        None => {}
+    }
+    if let Some(x) = options.priority {
+        if x > 10 {
+            return Err(format!("Invalid priority {}, use 0 to 10", x));
+        }
+        values.push(("priority", x.into()));
+    }
+    if let Some(x) = &options.format {
+        if Format::parse(x).is_none() {
+            return Err(format!(
+                "Invalid format {:?}, use markdown, html or plain",
+                x
+            ));
+        }
+        values.push(("format", x.trim().to_lowercase().into()));
+    }
+    if let Some(x) = &options.timezone {
+        if let Err(e) = x.parse::<Tz>() {
+            return Err(format!("Invalid timezone {:?} ({})", x, e));
+        }
+        values.push(("timezone", x.into()));
+    }
+    match options.max_length {
+        Some(0) => values.push(("max_length", Value::NULL)),
+        Some(x) => values.push(("max_length", x.into())),
+        None => {}
+    }
+    if let Some(x) = options.enabled {
+        values.push(("enabled", x.into()));
+    }
+    return Ok(values);
+}
+
+/**
+ * Fetch and parse a feed, to make sure the url is actually a feed.
+ */
+async fn check_url(url: &str) -> Result<Option<Feed>, String> {
+    let feed_conf = FeedConf {
+        url: url.to_owned(),
+        ..Default::default()
+    };
+    let epoch = DateTime::from_utc(NaiveDateTime::from_timestamp_opt(0, 0).unwrap(), Utc);
+    match rss_utils::fetch_feed(&feed_conf, epoch).await {
+        Ok(x) => return Ok(x),
+        Err(e) => return Err(format!("Could not fetch feed {:?} ({})", url, e)),
+    }
+}
+
+/**
+ * The title of a feed, or an empty string if it has none.
+ */
+fn feed_title(feed: &Option<Feed>) -> String {
+    return feed
+        .as_ref()
+        .and_then(|x| x.title.as_ref())
+        .map(|x| x.content.to_owned())
+        .unwrap_or_default();
+}
+
+/**
+ * Make sure the target a feed should send to exists.
+ */
+fn check_target(target_id: u32, conn: &mut Conn) -> Result<(), String> {
+    let targets = database::get_targets(conn).ok_or("Could not get targets")?;
+    if !targets.iter().any(|x| x.id == target_id) {
+        return Err(format!("There is no target with id {}", target_id));
+    }
+    return Ok(());
+}
+
+/**
+ * Add a feed, after making sure the url is a feed we can parse. Returns the
+ * id of the new feed, or None in a dry run.
+ */
+pub async fn create(
+    url: &str,
+    options: &FeedOptions,
+    conn: &mut Conn,
+) -> Result<Option<u32>, String> {
+    let has_target = options.target.is_some_and(|x| x != 0);
+    if !has_target && (options.push_url.is_none() || options.push_token.is_none()) {
+        return Err("A target or a push url and token are required when adding a feed".to_owned());
+    }
+    let mut values = column_values(options)?;
+    if let Some(x) = options.target.filter(|x| *x != 0) {
+        check_target(x, conn)?;
+    }
+    check_url(url).await?;
+    values.insert(0, ("url", url.into()));
+    if dryrun::enabled() {
+        return Ok(None);
+    }
+    match database::insert_feed(&values, conn) {
+        Some(id) => return Ok(Some(id)),
+        None => return Err("Could not add feed".to_owned()),
+    }
+}
+
+/**
+ * Change the config of a feed, a new url is checked the same way as when
+ * adding a feed. Returns false if there is no such feed.
+ */
+pub async fn update(
+    feed_id: u32,
+    url: Option<String>,
+    options: &FeedOptions,
+    conn: &mut Conn,
+) -> Result<bool, String> {
+    let mut values = column_values(options)?;
+    if let Some(x) = options.target.filter(|x| *x != 0) {
+        check_target(x, conn)?;
+    }
+    if let Some(url) = url {
+        check_url(&url).await?;
+        values.insert(0, ("url", url.into()));
+    }
+    return Ok(database::update_feed(feed_id, &values, conn));
+}
+
+/**
+ * Add a feed from the command line.
+ */
+pub async fn add(url: &str, options: &FeedOptions) -> bool {
+    let res_conn = connect();
+    if let None = res_conn {
+        return false;
+    }
+    match create(url, options, &mut res_conn.unwrap()).await {
+        Ok(Some(id)) => {
+            println!("Added feed {} {:?}", id, url);
+            return true;
+        }
+        Ok(None) => {
+            println!("Dry run, not adding feed {:?}", url);
+            return true;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    }
+}
+
+/**
+ * Print all feeds with their state.
+ */
+pub fn list() -> bool {
+    // Listing only reads, do not create tables or run migrations for it
+    if let Err(e) = database::check_ready() {
+        error!("{}", e);
+        return false;
+    }
+    let res_conn = database::new_conn();
+    if let None = res_conn {
+        return false;
+    }
+    let mut conn = res_conn.unwrap();
+    let res_feeds = database::get_feeds(&mut conn);
+    if let None = res_feeds {
+        return false;
+    }
+
+    println!(
+        "{:<5} {:<8} {:<20} {:<30} URL",
+        "ID", "ENABLED", "LAST FETCH", "LAST ERROR"
+    );
+    for feed in res_feeds.unwrap() {
+        let last_fetch = feed
+            .last_fetch
+            .and_then(|x| NaiveDateTime::from_timestamp_opt(x, 0))
+            .map(|x| x.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "never".to_owned());
+        let mut last_error: String = feed.last_error.unwrap_or_default();
+        if last_error.chars().count() > 30 {
+            last_error = format!("{}…", last_error.chars().take(29).collect::<String>());
+        }
+        println!(
+            "{:<5} {:<8} {:<20} {:<30} {}",
+            feed.id,
+            if feed.enabled { "yes" } else { "no" },
+            last_fetch,
+            last_error,
+            feed.url
+        );
+    }
+    return true;
+}
+
+/**
+ * Remove a feed and everything stored for it.
+ */
+pub fn remove(feed_id: u32) -> bool {
+    let res_conn = connect();
+    if let None = res_conn {
+        return false;
+    }
+    if !database::delete_feed(feed_id, &mut res_conn.unwrap()) {
+        error!("Could not remove feed {}", feed_id);
+        return false;
+    }
+    report(feed_id, "removed");
+    return true;
+}
+
+/**
+ * Change the config of a feed from the command line.
+ */
+pub async fn edit(feed_id: u32, url: Option<String>, options: &FeedOptions) -> bool {
+    let res_conn = connect();
+    if let None = res_conn {
+        return false;
+    }
+    match update(feed_id, url, options, &mut res_conn.unwrap()).await {
+        Ok(true) => {
+            report(feed_id, "updated");
+            return true;
+        }
+        Ok(false) => {
+            error!("Could not update feed {}", feed_id);
+            return false;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return false;
+        }
+    }
+}
+
+/**
+ * Enable or disable a feed, disabled feeds are not checked.
+ */
+pub fn set_enabled(feed_id: u32, enabled: bool) -> bool {
+    let res_conn = connect();
+    if let None = res_conn {
+        return false;
+    }
+    let values = [("enabled", enabled.into())];
+    if !database::update_feed(feed_id, &values, &mut res_conn.unwrap()) {
+        error!("Could not update feed {}", feed_id);
+        return false;
+    }
+    if enabled {
+        report(feed_id, "enabled");
+    } else {
+        report(feed_id, "disabled");
+    }
+    return true;
+}
+
+/**
+ * Fetch a feed and check its templates, without sending anything or writing
+ * to the database, and print what we found.
+ */
+pub async fn check(feed_id: u32) -> bool {
+    // Do not bootstrap, that could create tables or run migrations
+    if let Err(e) = database::check_ready() {
+        error!("{}", e);
+        return false;
+    }
+    let res_conn = database::new_conn();
+    if let None = res_conn {
+        return false;
+    }
+    let mut conn = res_conn.unwrap();
+    let res_feed_conf = find_feed(feed_id, &mut conn);
+    if let None = res_feed_conf {
+        return false;
+    }
+    let feed_conf = res_feed_conf.unwrap();
+    let mut ok = true;
+
+    println!("Feed {}: {}", feed_conf.id, feed_conf.url);
+    if !feed_conf.enabled {
+        println!("Disabled");
+    }
+    if let Some(e) = &feed_conf.last_error {
+        println!("Last error: {}", e);
+    }
+    match rss_utils::validate_templates(&feed_conf) {
+        Ok(_) => println!("Templates: ok"),
+        Err(e) => {
+            println!("Templates: {}", e);
+            ok = false;
+        }
+    }
+    match check_url(&feed_conf.url).await {
+        Ok(feed) => {
+            let last_fetch = feed_conf.last_fetch.unwrap_or(0);
+            let entries = feed.as_ref().map(|x| x.entries.len()).unwrap_or(0);
+            let new = feed
+                .as_ref()
+                .map(|x| {
+                    x.entries
+                        .iter()
+                        .filter(|x| x.published.is_none_or(|x| x.timestamp() >= last_fetch))
+                        .count()
+                })
+                .unwrap_or(0);
+            println!(
+                "Fetch: ok, {:?} has {} entries, {} new since the last fetch",
+                feed_title(&feed),
+                entries,
+                new
+            );
+        }
+        Err(e) => {
+            println!("Fetch: {}", e);
+            ok = false;
+        }
+    }
+    return ok;
+}
@@ -0,0 +1,69 @@
+use chrono::prelude::Utc;
+use log::error;
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/**
+ * When the main loop started and last completed an iteration that reached
+ * the database, in ms since the epoch, and how long it may take before we
+ * are unhealthy.
+ */
+static STARTED: AtomicI64 = AtomicI64::new(0);
+static LAST_CYCLE: AtomicI64 = AtomicI64::new(0);
+static MAX_AGE: AtomicI64 = AtomicI64::new(0);
+
+/**
+ * Start watching the main loop, it is unhealthy once it has not completed an
+ * iteration in `$HEALTH_INTERVALS` (defaults to 3) fetch intervals.
+ */
+pub fn start(interval_ms: u64) {
+    let intervals;
+    match env::var("HEALTH_INTERVALS") {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(x) if x > 0 => intervals = x,
+            _ => {
+                error!("Invalid $HEALTH_INTERVALS value {:#?}", val);
+                process::exit(1);
+            }
+        },
+        Err(_e) => intervals = 3,
+    }
+    MAX_AGE.store((interval_ms * intervals) as i64, Ordering::Relaxed);
+    STARTED.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+}
+
+/**
+ * Record that the main loop completed an iteration.
+ */
+pub fn cycle_completed() {
+    LAST_CYCLE.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+}
+
+/**
+ * Check wether the main loop is still doing its work. Before the first
+ * iteration completes, the time since the start counts.
+ */
+pub fn check() -> Result<(), String> {
+    let started = STARTED.load(Ordering::Relaxed);
+    if started == 0 {
+        return Err("The main loop has not started".to_owned());
+    }
+    let last_cycle = LAST_CYCLE.load(Ordering::Relaxed);
+    let age = Utc::now().timestamp_millis() - last_cycle.max(started);
+    let max_age = MAX_AGE.load(Ordering::Relaxed);
+    if age > max_age {
+        if last_cycle == 0 {
+            return Err(format!(
+                "No iteration of the main loop completed in {}s since the start",
+                age / 1000
+            ));
+        }
+        return Err(format!(
+            "The last iteration of the main loop completed {}s ago, more than {}s",
+            age / 1000,
+            max_age / 1000
+        ));
+    }
+    return Ok(());
+}
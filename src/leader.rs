@@ -0,0 +1,66 @@
+use crate::database;
+use crate::metrics;
+
+use log::{info, warn};
+use mysql::Conn;
+
+/**
+ * Makes sure only one instance checks the feeds when several run against the
+ * same database. The instance that holds a MySQL named lock is the leader,
+ * the others stand by and take over when it goes away. The lock lives as long
+ * as the connection we took it with.
+ */
+pub struct Leader {
+    name: String,
+    conn: Option<Conn>,
+    leader: Option<bool>,
+}
+
+impl Leader {
+    pub fn new() -> Leader {
+        return Leader {
+            name: database::lock_name(),
+            conn: None,
+            leader: None,
+        };
+    }
+
+    /**
+     * Try to become or stay the leader. Returns wether we are the leader, or
+     * None if the database could not be reached.
+     */
+    pub fn check(&mut self) -> Option<bool> {
+        let res = self.try_lead();
+        if let None = res {
+            // Whatever happened to the connection, the lock is not ours
+            // anymore once it is closed
+            self.conn = None;
+        }
+        if res != self.leader {
+            match res {
+                Some(true) => info!("Took the lock, this instance checks the feeds"),
+                Some(false) if self.leader == Some(true) => {
+                    warn!("Lost the lock, another instance checks the feeds now")
+                }
+                Some(false) => {
+                    info!("Another instance holds the lock and checks the feeds, standing by")
+                }
+                None => {}
+            }
+        }
+        self.leader = res;
+        metrics::leader(res == Some(true));
+        return res;
+    }
+
+    fn try_lead(&mut self) -> Option<bool> {
+        if let None = self.conn {
+            self.conn = Some(database::new_conn()?);
+        }
+        let conn = self.conn.as_mut().unwrap();
+        if database::holds_lock(&self.name, conn)? {
+            return Some(true);
+        }
+        return database::try_lock(&self.name, conn);
+    }
+}
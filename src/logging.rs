@@ -0,0 +1,32 @@
+use log::warn;
+use std::env;
+use std::io::{self, IsTerminal};
+use tracing_subscriber::EnvFilter;
+
+/**
+ * Set up logging, `$RUST_LOG` sets the level. By default every event is a
+ * line prefixed with the spans it happened in (like the feed and entry), with
+ * `$LOG_FORMAT=json` it is a json object holding the fields of those spans.
+ * Events logged with the `log` macros get the span fields too.
+ */
+pub fn init() {
+    let format = env::var("LOG_FORMAT").unwrap_or_default();
+    // Log to stderr like env_logger did, so the output of commands stays
+    // separate
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    if format == "json" {
+        builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init();
+        return;
+    }
+    builder.init();
+    if !format.is_empty() && format != "plain" {
+        warn!("Invalid $LOG_FORMAT value {:#?}, using plain", format);
+    }
+}
@@ -1,26 +1,138 @@
+#![allow(
+    clippy::needless_return,
+    clippy::needless_late_init,
+    clippy::redundant_pattern_matching,
+    clippy::unnecessary_unwrap
+)]
+
+mod api;
 mod database;
+mod dryrun;
+mod feeds;
+mod health;
+mod leader;
+mod logging;
+mod metrics;
 mod notify;
+mod preview;
+mod ratelimit;
 mod rss_utils;
-use database::FeedConf;
+mod server;
+mod shutdown;
+mod template;
+mod truncate;
+mod web;
+use database::{Backfill, FeedConf};
+use feeds::FeedOptions;
+use leader::Leader;
+use ratelimit::RateLimiter;
 
 use log::{debug, error, info, warn};
+use std::cmp::Reverse;
 use std::env;
 use std::process;
+use std::sync::Arc;
 
 use chrono::prelude::{DateTime, NaiveDateTime, Utc};
+use clap::{Parser, Subcommand};
 use feed_rs::model::Feed;
-use std::time::Duration;
+use mysql::Conn;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use tokio::time;
+use tracing::{info_span, Instrument};
+
+/**
+ * How long deliveries are kept in the history, in seconds.
+ */
+const DELIVERY_HISTORY: i64 = 30 * 24 * 60 * 60;
+
+/**
+ * What happened when a feed was checked.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FeedResult {
+    Ok,
+    NotModified,
+    Skipped,
+    FetchFailed,
+    NotifyFailed,
+}
+
+/**
+ * What happened in one iteration of the main loop.
+ */
+#[derive(Default)]
+struct Summary {
+    database_failed: bool,
+    /// Not all feeds were checked because we are shutting down
+    interrupted: bool,
+    results: Vec<(u32, FeedResult)>,
+}
+
+impl Summary {
+    /**
+     * Wether anything went wrong, feeds that are skipped because of invalid
+     * templates count as failed.
+     */
+    fn failed(&self) -> bool {
+        return self.database_failed
+            || self.interrupted
+            || self
+                .results
+                .iter()
+                .any(|(_, x)| *x != FeedResult::Ok && *x != FeedResult::NotModified);
+    }
+
+    /**
+     * Describe the iteration in a few lines, one for every problem.
+     */
+    fn lines(&self) -> Vec<String> {
+        if self.database_failed {
+            return vec!["Could not get feeds from the database".to_owned()];
+        }
+        let count = |result: FeedResult| self.results.iter().filter(|(_, x)| *x == result).count();
+        let mut lines = vec![format!(
+            "Checked {} feeds: {} ok, {} not modified, {} skipped, {} failed to fetch, {} failed to notify",
+            self.results.len(),
+            count(FeedResult::Ok),
+            count(FeedResult::NotModified),
+            count(FeedResult::Skipped),
+            count(FeedResult::FetchFailed),
+            count(FeedResult::NotifyFailed)
+        )];
+        for (id, result) in &self.results {
+            match result {
+                FeedResult::Skipped => lines.push(format!(
+                    "Feed {} was skipped, its templates are invalid",
+                    id
+                )),
+                FeedResult::FetchFailed => lines.push(format!("Feed {} could not be fetched", id)),
+                FeedResult::NotifyFailed => {
+                    lines.push(format!("Feed {} could not send all notifications", id))
+                }
+                _ => {}
+            }
+        }
+        if self.interrupted {
+            lines.push("Stopped early to shut down, not all feeds were checked".to_owned());
+        }
+        return lines;
+    }
+}
 
 /**
  * This calls fetch_feed, and figures out wether it succeeded or not.
- * It then pushes all _new_ entries to gotify, and returns the last fetched
- * time (now, or current if there is no new articles).
+ * It then pushes all _new_ entries to gotify. The last fetch time of the feed
+ * should only be updated if the result is Ok.
  */
-async fn get_feed(feed_conf: &FeedConf) -> bool {
-    // Check wether last_fetch_time is set, if it is not, we will use the "now"
-    // time as that. Which means that no articles will be found.
+async fn get_feed(feed_conf: &FeedConf, conn: &mut Conn, limiter: &mut RateLimiter) -> FeedResult {
+    // Check wether last_fetch_time is set, if it is not, this is the first
+    // fetch of the feed and we use the backfill setting. With no backfill we
+    // will use the "now" time as that. Which means that no articles will be
+    // found.
     let last_fetch_time;
+    let mut backfill_latest: Option<usize> = None;
     match &feed_conf.last_fetch {
         Some(x) => {
             last_fetch_time = DateTime::from_utc(
@@ -28,37 +140,102 @@ async fn get_feed(feed_conf: &FeedConf) -> bool {
                 Utc,
             )
         }
-        None => last_fetch_time = Utc::now(),
+        None => match &feed_conf.backfill {
+            Backfill::None => last_fetch_time = Utc::now(),
+            Backfill::Since(x) => {
+                info!("First fetch of feed, backfilling entries since {}", x);
+                last_fetch_time = x.to_owned();
+            }
+            Backfill::Latest(n) => {
+                info!("First fetch of feed, backfilling the latest {} entries", n);
+                last_fetch_time =
+                    DateTime::from_utc(NaiveDateTime::from_timestamp_opt(0, 0).unwrap(), Utc);
+                backfill_latest = Some(*n);
+            }
+        },
     }
     debug!("Using last_fetch_time {:?}", last_fetch_time.to_owned());
 
     // Fetch the feed and parse it
-    let res = rss_utils::fetch_feed(&feed_conf, last_fetch_time).await;
+    let res = rss_utils::fetch_feed(feed_conf, last_fetch_time).await;
     let feed_res: Option<Feed>;
     match res {
         Err(e) => {
             error!("Could not fetch feed ({:?})", e);
-            return false;
+            let error = format!("Could not fetch feed ({})", e);
+            database::set_feed_error(feed_conf.id, Some(&error), Utc::now().timestamp(), conn);
+            return FeedResult::FetchFailed;
         }
         Ok(x) => feed_res = x,
     }
+    // The fetch worked, so the only error left is a problem with the config
+    if feed_conf.last_error != feed_conf.config_error {
+        let error = feed_conf.config_error.as_deref();
+        database::set_feed_error(feed_conf.id, error, Utc::now().timestamp(), conn);
+    }
 
     // If feed is empty (we got status code 304), we should skip any further
     // processing
     if let None = feed_res {
-        return false;
+        return FeedResult::NotModified;
+    }
+    let mut feed = feed_res.unwrap();
+    metrics::entries_parsed(feed_conf.id, feed.entries.len());
+
+    // When backfilling the latest N entries, throw away everything else
+    if let Some(n) = backfill_latest {
+        feed.entries
+            .sort_by_key(|x| Reverse(x.published.or(x.updated)));
+        feed.entries.truncate(n);
     }
-    let feed = feed_res.unwrap();
 
     // Process all entries in the feed
-    let res_notif = notify::all(&feed, &feed_conf, last_fetch_time).await;
-    return res_notif;
+    if !notify::all(&feed, feed_conf, last_fetch_time, conn, limiter).await {
+        return FeedResult::NotifyFailed;
+    }
+    return FeedResult::Ok;
+}
+
+/**
+ * Check a single feed and send the notifications for new entries.
+ */
+async fn check_feed(feed: &FeedConf, conn: &mut Conn, limiter: &mut RateLimiter) -> FeedResult {
+    let time_now = Utc::now();
+
+    // Do not check feeds with broken templates, we would only send
+    // garbage notifications
+    if let Err(e) = rss_utils::validate_templates(feed) {
+        error!("Skipping feed {} ({})", feed.id, e);
+        database::set_feed_error(feed.id, Some(&e), time_now.timestamp(), conn);
+        return FeedResult::Skipped;
+    }
+
+    if let Some(e) = &feed.config_error {
+        warn!("Problems with the config of feed {} ({})", feed.id, e);
+    }
+
+    let result = get_feed(feed, conn, limiter).await;
+    if result == FeedResult::Ok {
+        database::update_last_fetch(feed.id, time_now.timestamp(), conn);
+    }
+    return result;
 }
 
 /**
  * This gets all feeds from the database and fetches them once.
  */
-async fn main_loop() {
+async fn main_loop(limiter: &mut RateLimiter) -> Summary {
+    let start = Instant::now();
+    let summary = check_feeds(limiter).await;
+    metrics::cycle(start.elapsed().as_secs_f64());
+    return summary;
+}
+
+/**
+ * Check all enabled feeds once, for main_loop.
+ */
+async fn check_feeds(limiter: &mut RateLimiter) -> Summary {
+    let mut summary = Summary::default();
     info!("========== Checking for new feed entries now");
 
     let res_conn = database::new_conn();
@@ -66,7 +243,8 @@ async fn main_loop() {
         error!(
             "Could not open database connection, waiting until next iteration before trying again!"
         );
-        return;
+        summary.database_failed = true;
+        return summary;
     };
     let mut conn = res_conn.unwrap();
 
@@ -74,26 +252,85 @@ async fn main_loop() {
 
     if let None = res_feeds {
         error!("Could not get feeds, waiting until next iteration before trying again!");
-        return;
+        summary.database_failed = true;
+        return summary;
     }
 
-    let feeds = res_feeds.unwrap();
+    let all_feeds = res_feeds.unwrap();
+    let feeds: Vec<&FeedConf> = all_feeds.iter().filter(|x| x.enabled).collect();
     info!("           Got {} feeds to check", feeds.len());
 
     for feed in feeds {
-        let time_now = Utc::now();
-        if get_feed(&feed).await {
-            database::update_last_fetch(feed.id, time_now.timestamp(), &mut conn);
+        // Finish the feed we are working on when asked to shut down, but do
+        // not start on the next one
+        if shutdown::requested() {
+            summary.interrupted = true;
+            break;
+        }
+        let span = info_span!("feed", feed_id = feed.id, feed_url = %feed.url);
+        let result = check_feed(feed, &mut conn, limiter).instrument(span).await;
+        summary.results.push((feed.id, result));
+    }
+
+    // The quiet hours queues are released for every feed, also the ones that
+    // are disabled or failed, so nothing stays queued forever
+    for feed in &all_feeds {
+        if summary.interrupted {
+            break;
+        }
+        let span = info_span!("feed", feed_id = feed.id, feed_url = %feed.url);
+        if notify::release_queue(feed, &mut conn).instrument(span).await {
+            continue;
+        }
+        let result = summary.results.iter_mut().find(|(id, _)| *id == feed.id);
+        if let Some((_, x)) = result {
+            if *x != FeedResult::FetchFailed {
+                *x = FeedResult::NotifyFailed;
+            }
         }
     }
+    database::prune_deliveries(Utc::now().timestamp() - DELIVERY_HISTORY, &mut conn);
+    return summary;
 }
 
 /**
- * Main app, sets up database, and then it keeps an active loop.
+ * Main app, sets up database, and then it keeps an active loop. With `once`
+ * the feeds are only checked a single time, and a summary is printed. Returns
+ * false if anything failed. On SIGTERM or SIGINT the feed that is being
+ * checked is finished, and the loop stops.
  */
-async fn app() {
+async fn app(once: bool) -> bool {
     database::bootstrap();
 
+    let mut limiter = RateLimiter::from_env();
+    shutdown::listen();
+
+    // A dry run sends nothing, so it does not need to keep other instances
+    // from checking the feeds
+    let mut leader = if dryrun::enabled() {
+        None
+    } else {
+        Some(Leader::new())
+    };
+
+    if once {
+        if let Some(ref mut x) = leader {
+            match x.check() {
+                Some(true) => {}
+                Some(false) => {
+                    println!("Another instance is checking the feeds");
+                    return true;
+                }
+                None => return false,
+            }
+        }
+        let summary = main_loop(&mut limiter).await;
+        for line in summary.lines() {
+            println!("{}", line);
+        }
+        return !summary.failed();
+    }
+
     let interval_timeout;
     match env::var("FETCH_INTERVAL") {
         Ok(val) => {
@@ -110,17 +347,181 @@ async fn app() {
         }
     }
 
+    health::start(interval_timeout);
+    let fetch_now = Arc::new(Notify::new());
+    server::start(fetch_now.clone());
+
     let mut interval = time::interval(Duration::from_millis(interval_timeout));
     loop {
-        main_loop().await;
-        interval.tick().await;
+        match leader.as_mut().map(|x| x.check()) {
+            Some(Some(false)) => {
+                // Standing by is all the work there is
+                health::cycle_completed();
+            }
+            Some(None) => error!("Could not check which instance checks the feeds"),
+            _ => {
+                let summary = main_loop(&mut limiter).await;
+                for line in summary.lines() {
+                    info!("{}", line);
+                }
+                if !summary.database_failed {
+                    health::cycle_completed();
+                }
+            }
+        }
+        if shutdown::requested() {
+            info!("Stopped rss-watcher");
+            return true;
+        }
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = fetch_now.notified() => interval.reset(),
+            _ = shutdown::wait() => {
+                info!("Stopped rss-watcher");
+                return true;
+            }
+        }
     }
 }
 
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Checks RSS feeds for new entries, and pushes those to Gotify"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Check all feeds once and exit, with a non-zero status if any feed
+    /// failed
+    #[arg(long, global = true)]
+    once: bool,
+    /// Fetch and render everything as usual, but only log the notifications
+    /// instead of sending them, and do not write anything to the database
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Watch the feeds and send notifications for new entries, this is the
+    /// default
+    Run,
+    /// Add, list, change and remove feeds
+    Feeds {
+        #[command(subcommand)]
+        command: FeedsCommand,
+    },
+    /// Fetch a feed and check its config, without sending anything
+    Check {
+        /// Id of the feed
+        id: u32,
+    },
+    /// Render the templates for the latest entries of a feed and print what
+    /// would be sent, without sending or recording anything
+    Preview {
+        /// Url of the feed, or path to a local file
+        source: String,
+        /// Use the config (templates, priority etc.) of this feed in the database
+        #[arg(long)]
+        feed: Option<u32>,
+        /// Title template to use instead of the one from the feed config
+        #[arg(long)]
+        title: Option<String>,
+        /// Message template to use instead of the one from the feed config
+        #[arg(long)]
+        message: Option<String>,
+        /// Output format (markdown, html or plain) to use instead of the one
+        /// from the feed config
+        #[arg(long)]
+        format: Option<String>,
+        /// Maximum length of the title and message to use instead of the one
+        /// from the feed config
+        #[arg(long)]
+        max_length: Option<u32>,
+        /// How many of the latest entries to render
+        #[arg(short = 'n', long, default_value_t = 5)]
+        count: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum FeedsCommand {
+    /// Add a feed, the url is fetched first to make sure it is a feed
+    Add {
+        /// Url of the RSS/Atom feed
+        url: String,
+        #[command(flatten)]
+        options: FeedOptions,
+    },
+    /// List all feeds
+    List,
+    /// Remove a feed
+    Remove {
+        /// Id of the feed
+        id: u32,
+    },
+    /// Change the config of a feed
+    Edit {
+        /// Id of the feed
+        id: u32,
+        /// New url of the feed, it is fetched first to make sure it is a feed
+        #[arg(long)]
+        url: Option<String>,
+        #[command(flatten)]
+        options: FeedOptions,
+    },
+    /// Start checking a feed again
+    Enable {
+        /// Id of the feed
+        id: u32,
+    },
+    /// Stop checking a feed, without removing it
+    Disable {
+        /// Id of the feed
+        id: u32,
+    },
+}
+
 fn main() {
-    env_logger::init();
-    info!("Starting rss-watcher");
+    let cli = Cli::parse();
+    logging::init();
+    if cli.dry_run {
+        dryrun::enable();
+    }
     let rt = tokio::runtime::Runtime::new().unwrap();
-    let future = app();
-    rt.block_on(future);
+    let ok;
+    match cli.command {
+        Some(Command::Feeds { command }) => match command {
+            FeedsCommand::Add { url, options } => ok = rt.block_on(feeds::add(&url, &options)),
+            FeedsCommand::List => ok = feeds::list(),
+            FeedsCommand::Remove { id } => ok = feeds::remove(id),
+            FeedsCommand::Edit { id, url, options } => {
+                ok = rt.block_on(feeds::edit(id, url, &options))
+            }
+            FeedsCommand::Enable { id } => ok = feeds::set_enabled(id, true),
+            FeedsCommand::Disable { id } => ok = feeds::set_enabled(id, false),
+        },
+        Some(Command::Check { id }) => ok = rt.block_on(feeds::check(id)),
+        Some(Command::Preview {
+            source,
+            feed,
+            title,
+            message,
+            format,
+            max_length,
+            count,
+        }) => {
+            ok = rt.block_on(preview::run(
+                &source, feed, title, message, format, max_length, count,
+            ));
+        }
+        Some(Command::Run) | None => {
+            info!("Starting rss-watcher");
+            ok = rt.block_on(app(cli.once));
+        }
+    }
+    if !ok {
+        process::exit(1);
+    }
 }
@@ -0,0 +1,174 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::sync::OnceLock;
+
+/**
+ * All metrics we export, registered in our own registry.
+ */
+struct Metrics {
+    registry: Registry,
+    fetches: IntCounterVec,
+    fetch_duration: HistogramVec,
+    entries_parsed: IntCounterVec,
+    entries: IntCounterVec,
+    notification_failures: IntCounterVec,
+    cycle_duration: Histogram,
+    db_errors: IntCounter,
+    leader: IntGauge,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+fn counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let counter = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+    registry.register(Box::new(counter.clone())).unwrap();
+    return counter;
+}
+
+fn metrics() -> &'static Metrics {
+    return METRICS.get_or_init(|| {
+        let registry = Registry::new();
+        let fetches = counter_vec(
+            &registry,
+            "rss_watcher_fetches_total",
+            "Feed fetches by feed and outcome (HTTP status code or error)",
+            &["feed", "outcome"],
+        );
+        let entries_parsed = counter_vec(
+            &registry,
+            "rss_watcher_entries_parsed_total",
+            "Entries found in fetched feeds",
+            &["feed"],
+        );
+        let entries = counter_vec(
+            &registry,
+            "rss_watcher_entries_total",
+            "Entries by what happened to them (notified, failed, skipped or filtered)",
+            &["feed", "result"],
+        );
+        let notification_failures = counter_vec(
+            &registry,
+            "rss_watcher_notification_failures_total",
+            "Notifications that could not be sent, by gotify server",
+            &["target"],
+        );
+        let fetch_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "rss_watcher_fetch_duration_seconds",
+                "How long fetching and parsing a feed took",
+            ),
+            &["feed"],
+        )
+        .unwrap();
+        registry.register(Box::new(fetch_duration.clone())).unwrap();
+        let cycle_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "rss_watcher_cycle_duration_seconds",
+                "How long checking all feeds once took",
+            )
+            .buckets(vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]),
+        )
+        .unwrap();
+        registry.register(Box::new(cycle_duration.clone())).unwrap();
+        let db_errors =
+            IntCounter::new("rss_watcher_db_errors_total", "Failed database operations").unwrap();
+        registry.register(Box::new(db_errors.clone())).unwrap();
+        let leader = IntGauge::new(
+            "rss_watcher_leader",
+            "1 if this instance holds the lock and checks the feeds",
+        )
+        .unwrap();
+        registry.register(Box::new(leader.clone())).unwrap();
+
+        return Metrics {
+            registry,
+            fetches,
+            fetch_duration,
+            entries_parsed,
+            entries,
+            notification_failures,
+            cycle_duration,
+            db_errors,
+            leader,
+        };
+    });
+}
+
+/**
+ * Record a fetch of a feed, `outcome` is the HTTP status code or `error`.
+ */
+pub fn fetch(feed_id: u32, outcome: &str, seconds: f64) {
+    let feed = feed_id.to_string();
+    let m = metrics();
+    m.fetches.with_label_values(&[&feed, outcome]).inc();
+    m.fetch_duration
+        .with_label_values(&[&feed])
+        .observe(seconds);
+}
+
+/**
+ * Record how many entries a fetched feed had.
+ */
+pub fn entries_parsed(feed_id: u32, count: usize) {
+    metrics()
+        .entries_parsed
+        .with_label_values(&[&feed_id.to_string()])
+        .inc_by(count as u64);
+}
+
+/**
+ * Record what happened to an entry, `notified`, `failed` (it could not be
+ * sent or queued), `skipped` (it is older than the last fetch) or `filtered`
+ * (it hit the rate limit).
+ */
+pub fn entry(feed_id: u32, result: &str) {
+    metrics()
+        .entries
+        .with_label_values(&[&feed_id.to_string(), result])
+        .inc();
+}
+
+/**
+ * Record a notification that could not be sent to a gotify server.
+ */
+pub fn notification_failed(target: &str) {
+    metrics()
+        .notification_failures
+        .with_label_values(&[target])
+        .inc();
+}
+
+/**
+ * Record how long an iteration of the main loop took.
+ */
+pub fn cycle(seconds: f64) {
+    metrics().cycle_duration.observe(seconds);
+}
+
+/**
+ * Record a failed database operation.
+ */
+pub fn db_error() {
+    metrics().db_errors.inc();
+}
+
+/**
+ * Record wether this instance is the one checking the feeds.
+ */
+pub fn leader(leader: bool) {
+    metrics().leader.set(leader as i64);
+}
+
+/**
+ * All metrics in the Prometheus text format.
+ */
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        return format!("# Could not encode metrics ({})\n", e);
+    }
+    return String::from_utf8(buffer).unwrap_or_default();
+}
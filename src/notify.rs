@@ -1,38 +1,110 @@
-use crate::database::FeedConf;
+use crate::database::{self, FeedConf, Format};
+use crate::dryrun;
+use crate::metrics;
+use crate::ratelimit::RateLimiter;
 use crate::rss_utils;
+use crate::template;
+use crate::truncate;
 
-use chrono::prelude::{DateTime, Utc};
-use feed_rs::model::Feed;
-use log::{error, info};
+use chrono::prelude::{DateTime, NaiveTime, Utc};
+use feed_rs::model::{self, Feed};
+use log::{debug, error, info, warn};
+use mysql::Conn;
+use serde_json::{json, Map, Value};
+use std::time::Instant;
+use tracing::{info_span, Instrument};
 
 /**
- * Push feed entry to gotify
+ * A rendered notification, ready to be pushed. The title and message are
+ * already in the output format of the feed. `image` is shown as a big picture
+ * by the Android client.
  */
-async fn gotify(
-    title: String,
-    message: String,
-    link: Option<String>,
-    feed_conf: &FeedConf,
-) -> Result<(), reqwest::Error> {
-    let uri = format!("{}/message", &feed_conf.push_url);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    pub link: Option<String>,
+    pub image: Option<String>,
+    pub priority: u32,
+}
 
-    // Build json string that will be sent as payload to gotify
-    let mut req = "{".to_owned();
+/**
+ * The gotify content type for an output format. Gotify only knows plain text
+ * and markdown, its markdown renderer also renders inline HTML, so HTML is
+ * sent as markdown.
+ */
+fn content_type(format: Format) -> &'static str {
+    match format {
+        Format::Markdown | Format::Html => return "text/markdown",
+        Format::Plain => return "text/plain",
+    }
+}
 
-    req.push_str(format!("\"title\":\"{}\"", title).as_str());
-    req.push_str(format!(",\"message\":\"{}\"", message).as_str());
-    req.push_str(",\"priority\":1");
+/**
+ * Merge `extra` into `base`, objects are merged key by key and anything else
+ * in `extra` replaces what is in `base`.
+ */
+fn merge(base: &mut Map<String, Value>, extra: &Map<String, Value>) {
+    for (key, value) in extra {
+        match (base.get_mut(key), value) {
+            (Some(Value::Object(x)), Value::Object(y)) => merge(x, y),
+            _ => {
+                base.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+}
 
-    req.push_str(",\"extras\": {");
-    req.push_str("\"client::display\": { \"contentType\": \"text/markdown\" }");
-    if link.is_some() {
-        req.push_str(",\"client::notification\": { \"click\": { \"url\": \"");
-        req.push_str(link.unwrap().as_str());
-        req.push_str("\"}}")
+/**
+ * Build the json string that will be sent as payload to gotify. The extras of
+ * the feed are merged into the ones we set.
+ */
+pub fn gotify_payload(notification: &Notification, feed_conf: &FeedConf) -> String {
+    let mut extras = Map::new();
+    extras.insert(
+        "client::display".to_owned(),
+        json!({ "contentType": content_type(feed_conf.format) }),
+    );
+    let mut client_notification = Map::new();
+    if let Some(link) = &notification.link {
+        client_notification.insert("click".to_owned(), json!({ "url": link }));
+    }
+    if let Some(image) = &notification.image {
+        client_notification.insert("bigImageUrl".to_owned(), json!(image));
+    }
+    if !client_notification.is_empty() {
+        extras.insert(
+            "client::notification".to_owned(),
+            Value::Object(client_notification),
+        );
+    }
+    if let Some(x) = &feed_conf.extras {
+        merge(&mut extras, x);
+    }
+
+    let req = json!({
+        "title": notification.title,
+        "message": notification.message,
+        "priority": notification.priority,
+        "extras": extras,
+    });
+    return req.to_string();
+}
+
+/**
+ * Push notification to gotify, a response other than a success counts as an
+ * error too.
+ */
+async fn gotify(notification: &Notification, feed_conf: &FeedConf) -> Result<(), String> {
+    let uri = format!("{}/message", &feed_conf.push_url);
+    let req = gotify_payload(notification, feed_conf);
+    if dryrun::enabled() {
+        info!("Dry run, not sending to {}: {}", uri, req);
+        return Ok(());
     }
-    req.push_str("}}");
 
     // Send request to gotify
+    let start = Instant::now();
     let client = reqwest::Client::new();
     let res = client
         .post(uri)
@@ -40,45 +112,605 @@ async fn gotify(
         .body(req.to_owned())
         .header("Content-Type", "application/json")
         .send()
-        .await?;
-    if res.status().is_success() {
-        info!("Sent notification with title \"{}\"", title);
-    } else {
+        .await
+        .map_err(|e| e.to_string())?;
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let status = res.status().as_u16();
+    if !res.status().is_success() {
         error!("payload: {}", req);
-        error!("Could not send notification... {:#?}", res);
+        tracing::error!(
+            notifier = "gotify",
+            status,
+            duration_ms,
+            "Gotify answered {}",
+            status
+        );
+        return Err(format!("gotify answered {}", res.status()));
     }
+    tracing::info!(
+        notifier = "gotify",
+        status,
+        duration_ms,
+        "Sent notification with title \"{}\"",
+        notification.title
+    );
     Ok(())
 }
 
+/**
+ * Push a notification to gotify and record in the delivery history wether
+ * that worked.
+ */
+async fn send(notification: &Notification, feed_conf: &FeedConf, conn: &mut Conn) -> bool {
+    let res = gotify(notification, feed_conf).await;
+    let now = Utc::now().timestamp();
+    match res {
+        Ok(_) => {
+            database::record_delivery(feed_conf.id, notification, "sent", None, now, conn);
+            return true;
+        }
+        Err(e) => {
+            error!("Could not send push notification ({})", e);
+            metrics::notification_failed(&feed_conf.push_url);
+            database::record_delivery(feed_conf.id, notification, "failed", Some(&e), now, conn);
+            return false;
+        }
+    }
+}
+
+/**
+ * Figure out which priority an entry should be sent with. This is the feed
+ * default, unless one or more priority rules match the entry, in which case
+ * the highest priority of the matching rules is used.
+ */
+fn priority(entry: &model::Entry, ctx: &template::Value, feed_conf: &FeedConf) -> u32 {
+    let mut priority: Option<u32> = None;
+    for rule in &feed_conf.priority_rules {
+        let value = rss_utils::fill_template_field(&rule.field, ctx);
+        if value.to_lowercase().contains(&rule.pattern.to_lowercase()) {
+            debug!(
+                "Priority rule {:?} matched entry {:?}",
+                rule.pattern, entry.id
+            );
+            priority = Some(priority.map_or(rule.priority, |p| p.max(rule.priority)));
+        }
+    }
+    return priority.unwrap_or(feed_conf.priority);
+}
+
+/**
+ * Check wether `now` is within the quiet hours of the feed, or of its target
+ * when the feed has none. The window is given as `HH:MM` in the timezone of
+ * the feed, and may wrap around midnight (e.g. 22:00 - 07:00).
+ */
+pub fn in_quiet_hours(feed_conf: &FeedConf, now: DateTime<Utc>) -> bool {
+    let (start, end) = match (&feed_conf.quiet_start, &feed_conf.quiet_end) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return false,
+    };
+    let start_res = NaiveTime::parse_from_str(start, "%H:%M");
+    let end_res = NaiveTime::parse_from_str(end, "%H:%M");
+    if start_res.is_err() || end_res.is_err() {
+        warn!(
+            "Invalid quiet hours {:?} - {:?} for feed {}, ignoring them",
+            start, end, feed_conf.id
+        );
+        return false;
+    }
+    let (start, end) = (start_res.unwrap(), end_res.unwrap());
+    let local_time = now.with_timezone(&feed_conf.tz).time();
+
+    if start <= end {
+        return start <= local_time && local_time < end;
+    }
+    return local_time >= start || local_time < end;
+}
+
+/**
+ * Either send the notification right away, or put it in the queue if the feed
+ * is in its quiet hours and the notification is not important enough to
+ * override them.
+ */
+async fn deliver(notification: &Notification, feed_conf: &FeedConf, conn: &mut Conn) -> bool {
+    let now = Utc::now();
+    let overrides = match feed_conf.quiet_override {
+        Some(x) => notification.priority >= x,
+        None => false,
+    };
+    if !overrides && in_quiet_hours(feed_conf, now) {
+        info!(
+            "Quiet hours, queueing notification with title \"{}\"",
+            notification.title
+        );
+        if !database::queue_notification(feed_conf.id, notification, now.timestamp(), conn) {
+            return false;
+        }
+        database::record_delivery(
+            feed_conf.id,
+            notification,
+            "queued",
+            None,
+            now.timestamp(),
+            conn,
+        );
+        return true;
+    }
+
+    return send(notification, feed_conf, conn).await;
+}
+
+/**
+ * Build a single notification summarizing all the given notifications.
+ */
+fn digest(notifications: &[Notification], format: Format) -> Notification {
+    let mut message = "".to_owned();
+    for (i, notification) in notifications.iter().enumerate() {
+        match (&notification.link, format) {
+            (Some(link), Format::Markdown) => {
+                message.push_str(format!("- [{}]({})", notification.title, link).as_str())
+            }
+            (Some(link), Format::Html) => message
+                .push_str(format!("- <a href=\"{}\">{}</a>", link, notification.title).as_str()),
+            (Some(link), Format::Plain) => {
+                message.push_str(format!("- {} ({})", notification.title, link).as_str())
+            }
+            (None, _) => message.push_str(format!("- {}", notification.title).as_str()),
+        }
+        if i < (notifications.len() - 1) {
+            match format {
+                Format::Html => message.push_str("<br>\n"),
+                _ => message.push('\n'),
+            }
+        }
+    }
+    return Notification {
+        title: format!("{} notifications during quiet hours", notifications.len()),
+        message,
+        link: None,
+        image: None,
+        priority: notifications.iter().map(|n| n.priority).max().unwrap_or(0),
+    };
+}
+
+/**
+ * Send all notifications queued during the quiet hours of the feed, if the
+ * quiet hours are over. Depending on the feed config they are sent one by one
+ * or as a single digest.
+ */
+pub async fn release_queue(feed_conf: &FeedConf, conn: &mut Conn) -> bool {
+    if in_quiet_hours(feed_conf, Utc::now()) {
+        return true;
+    }
+    let res_queued = database::get_queued_notifications(feed_conf.id, conn);
+    if let None = res_queued {
+        return false;
+    }
+    let queued = res_queued.unwrap();
+    if queued.is_empty() {
+        return true;
+    }
+    info!(
+        "Quiet hours are over, releasing {} queued notifications",
+        queued.len()
+    );
+
+    if feed_conf.quiet_digest {
+        let notifications: Vec<Notification> = queued.iter().map(|(_, n)| n.clone()).collect();
+        if !send(&digest(&notifications, feed_conf.format), feed_conf, conn).await {
+            return false;
+        }
+        for (queue_id, _) in &queued {
+            database::delete_queued_notification(*queue_id, conn);
+        }
+        return true;
+    }
+
+    let mut all_notifs_successfull = true;
+    for (queue_id, notification) in &queued {
+        if !send(notification, feed_conf, conn).await {
+            all_notifs_successfull = false;
+            continue;
+        }
+        database::delete_queued_notification(*queue_id, conn);
+    }
+    return all_notifs_successfull;
+}
+
+/**
+ * The feed icon (or logo) as an image in the output format, or None for
+ * plain text.
+ */
+fn feed_icon(feed: &Feed, format: Format) -> Option<String> {
+    let image = feed.icon.as_ref().or(feed.logo.as_ref())?;
+    match format {
+        Format::Markdown => return Some(format!("![]({})", image.uri)),
+        Format::Html => return Some(format!("<img src=\"{}\">", image.uri.replace('"', "%22"))),
+        Format::Plain => return None,
+    }
+}
+
+/**
+ * Render a notification for an entry with the given title and message
+ * templates. If the feed has a maximum length, the title and message are
+ * truncated to it, the message with a link to the entry.
+ */
+pub fn render(
+    title_template: &str,
+    message_template: &str,
+    entry: &model::Entry,
+    feed: &Feed,
+    feed_conf: &FeedConf,
+) -> Notification {
+    // Building the context converts all text of the entry, so do it once
+    let ctx = rss_utils::template_context(entry, feed, feed_conf);
+    let mut title = rss_utils::fill_template(title_template, &ctx, feed_conf);
+    let mut message = rss_utils::fill_template(message_template, &ctx, feed_conf);
+    if feed_conf.feed_icon {
+        if let Some(icon) = feed_icon(feed, feed_conf.format) {
+            message = format!("{}\n\n{}", icon, message);
+        }
+    }
+    // Escape before truncating, so the escaped text is what counts towards the
+    // maximum length. Truncating never cuts entities in half.
+    title = rss_utils::escape(title, feed_conf.format);
+    message = rss_utils::escape(message, feed_conf.format);
+    let link = entry.links.first().map(|x| x.href.as_str());
+    if let Some(max_length) = feed_conf.max_length {
+        title = truncate::truncate(&title, max_length as usize, None, feed_conf.format);
+        message = truncate::truncate(&message, max_length as usize, link, feed_conf.format);
+    }
+    let mut image: Option<String> = None;
+    if feed_conf.big_image {
+        image = rss_utils::entry_image(entry);
+    }
+
+    return Notification {
+        title,
+        message,
+        link: link.map(|x| x.to_owned()),
+        image,
+        priority: priority(entry, &ctx, feed_conf),
+    };
+}
+
+/**
+ * What changed about an entry since we last saw it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seen {
+    Unchanged,
+    New,
+    Updated,
+}
+
+/**
+ * Compare the content hash and updated timestamp of an entry with what we
+ * stored when we last saw it, if we did.
+ */
+fn classify(hash: &str, updated: Option<i64>, seen: Option<(String, Option<i64>)>) -> Seen {
+    match seen {
+        Some((seen_hash, seen_updated)) if seen_hash == hash && seen_updated == updated => {
+            return Seen::Unchanged
+        }
+        Some(_) => return Seen::Updated,
+        None => return Seen::New,
+    }
+}
+
+/**
+ * Check wether an entry we have seen before has changed since last time, by
+ * comparing the content hash and updated timestamp with what we stored.
+ */
+fn seen_state(entry: &model::Entry, feed_conf: &FeedConf, conn: &mut Conn) -> Seen {
+    let hash = rss_utils::entry_hash(entry);
+    let updated = entry.updated.map(|x| x.timestamp());
+    let seen = database::get_seen_entry(feed_conf.id, &entry.id, conn);
+    return classify(&hash, updated, seen);
+}
+
+/**
+ * Store the current state of an entry, see seen_state.
+ */
+fn save_seen(entry: &model::Entry, feed_conf: &FeedConf, conn: &mut Conn) {
+    let hash = rss_utils::entry_hash(entry);
+    let updated = entry.updated.map(|x| x.timestamp());
+    database::save_seen_entry(feed_conf.id, &entry.id, &hash, updated, conn);
+}
+
+/**
+ * Describe the entries that were not sent because of rate limiting, e.g.
+ * "2 more new and 1 more updated entries".
+ */
+fn overflow_entries(new: u32, updated: u32) -> String {
+    let plural = |n: u32| if n == 1 { "entry" } else { "entries" };
+    match (new, updated) {
+        (_, 0) => return format!("{} more new {}", new, plural(new)),
+        (0, _) => return format!("{} more updated {}", updated, plural(updated)),
+        _ => {
+            return format!(
+                "{} more new and {} more updated {}",
+                new,
+                updated,
+                plural(new + updated)
+            )
+        }
+    }
+}
+
 /**
  * Push all new entries in the feed as per the configuration
  */
-pub async fn all(feed: &Feed, feed_conf: &FeedConf, last_fetch_time: DateTime<Utc>) -> bool {
+pub async fn all(
+    feed: &Feed,
+    feed_conf: &FeedConf,
+    last_fetch_time: DateTime<Utc>,
+    conn: &mut Conn,
+    limiter: &mut RateLimiter,
+) -> bool {
     let mut all_notifs_successfull = true;
+    let mut sent: u32 = 0;
+    let mut overflow_new: u32 = 0;
+    let mut overflow_updated: u32 = 0;
 
-    // Skip sending notification if the publish time is before the
-    // last_fetch_time
     for entry in &feed.entries {
-        if let Some(x) = entry.published {
-            if last_fetch_time > x {
-                info!("Skipping entry that was published at {}", x);
-                continue;
+        let span = info_span!("entry", entry_id = %entry.id);
+        let mut seen = Seen::Unchanged;
+        if feed_conf.notify_updates {
+            seen = span.in_scope(|| seen_state(entry, feed_conf, conn));
+        }
+        // New entries are remembered right away, updates only once the update
+        // is delivered, so an update we could not send is tried again
+        if seen == Seen::New {
+            save_seen(entry, feed_conf, conn);
+        }
+        let res_notification = span.in_scope(|| {
+            if seen == Seen::Updated {
+                info!("Entry {:?} was updated since last time", entry.id);
+                return Some(render(
+                    &feed_conf.update_title,
+                    &feed_conf.update_message,
+                    entry,
+                    feed,
+                    feed_conf,
+                ));
+            }
+            // Skip sending notification if the publish time is before the
+            // last_fetch_time
+            if let Some(x) = entry.published {
+                if last_fetch_time > x {
+                    info!("Skipping entry that was published at {}", x);
+                    metrics::entry(feed_conf.id, "skipped");
+                    return None;
+                }
             }
+            return Some(render(
+                &feed_conf.title,
+                &feed_conf.message,
+                entry,
+                feed,
+                feed_conf,
+            ));
+        });
+        if let None = res_notification {
+            continue;
         }
+        let notification = res_notification.unwrap();
 
-        // Get the fields we want to send to gotify
-        let title = rss_utils::fill_template(&feed_conf.title, &entry, &feed);
-        let message = rss_utils::fill_template(&feed_conf.message, &entry, &feed);
-        let mut link: Option<String> = None;
-        if entry.links.len() > 0 {
-            link = Some(rss_utils::escape(entry.links[0].href.to_owned()));
+        let now = Utc::now();
+        if !limiter.allow(feed_conf, sent, now) {
+            metrics::entry(feed_conf.id, "filtered");
+            if seen == Seen::Updated {
+                overflow_updated += 1;
+            } else {
+                overflow_new += 1;
+            }
+            continue;
+        }
+
+        if deliver(&notification, feed_conf, conn)
+            .instrument(span)
+            .await
+        {
+            metrics::entry(feed_conf.id, "notified");
+            if seen == Seen::Updated {
+                save_seen(entry, feed_conf, conn);
+            }
+        } else {
+            metrics::entry(feed_conf.id, "failed");
+            all_notifs_successfull = false;
         }
+        limiter.record(feed_conf, now);
+        sent += 1;
+    }
 
-        if let Err(e) = gotify(title, message, link, &feed_conf).await {
-            error!("Could not send push notification ({:#?})", e);
+    // Collapse everything we did not send because of rate limiting into a
+    // single notification, so nothing is dropped silently.
+    let overflow = overflow_new + overflow_updated;
+    if overflow > 0 {
+        warn!(
+            "Rate limit hit for feed {}, collapsing {} entries into one notification",
+            feed_conf.id, overflow
+        );
+        let mut link: Option<String> = None;
+        if !feed.links.is_empty() {
+            link = Some(feed.links[0].href.to_owned());
+        }
+        let notification = Notification {
+            title: rss_utils::escape(format!("...and {} more items", overflow), feed_conf.format),
+            message: rss_utils::escape(
+                format!(
+                    "{} in {} were not sent because of rate limiting",
+                    overflow_entries(overflow_new, overflow_updated),
+                    feed_conf.url
+                ),
+                feed_conf.format,
+            ),
+            link,
+            image: None,
+            priority: feed_conf.priority,
+        };
+        if !deliver(&notification, feed_conf, conn).await {
             all_notifs_successfull = false;
         }
+        limiter.record(feed_conf, Utc::now());
     }
 
     return all_notifs_successfull;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn quiet_conf(start: &str, end: &str, timezone: &str) -> FeedConf {
+        return FeedConf {
+            quiet_start: Some(start.to_owned()),
+            quiet_end: Some(end.to_owned()),
+            timezone: timezone.to_owned(),
+            tz: timezone.parse().unwrap(),
+            ..Default::default()
+        };
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        return Utc.with_ymd_and_hms(2024, 1, 2, hour, minute, 0).unwrap();
+    }
+
+    fn notification(title: &str, link: Option<&str>, priority: u32) -> Notification {
+        return Notification {
+            title: title.to_owned(),
+            message: "message".to_owned(),
+            link: link.map(|x| x.to_owned()),
+            image: None,
+            priority,
+        };
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let conf = quiet_conf("09:00", "17:00", "UTC");
+        assert!(!in_quiet_hours(&conf, at(8, 59)));
+        assert!(in_quiet_hours(&conf, at(9, 0)));
+        assert!(in_quiet_hours(&conf, at(16, 59)));
+        assert!(!in_quiet_hours(&conf, at(17, 0)));
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight() {
+        let conf = quiet_conf("22:00", "07:00", "UTC");
+        assert!(in_quiet_hours(&conf, at(22, 0)));
+        assert!(in_quiet_hours(&conf, at(23, 59)));
+        assert!(in_quiet_hours(&conf, at(0, 0)));
+        assert!(in_quiet_hours(&conf, at(6, 59)));
+        assert!(!in_quiet_hours(&conf, at(7, 0)));
+        assert!(!in_quiet_hours(&conf, at(12, 0)));
+        assert!(!in_quiet_hours(&conf, at(21, 59)));
+    }
+
+    #[test]
+    fn quiet_hours_timezone() {
+        // 22:00 UTC is 07:00 in Tokyo, 21:00 UTC is 06:00
+        let conf = quiet_conf("22:00", "07:00", "Asia/Tokyo");
+        assert!(in_quiet_hours(&conf, at(21, 0)));
+        assert!(!in_quiet_hours(&conf, at(22, 0)));
+    }
+
+    #[test]
+    fn no_or_invalid_quiet_hours() {
+        assert!(!in_quiet_hours(&FeedConf::default(), at(0, 0)));
+        let mut conf = quiet_conf("22:00", "07:00", "UTC");
+        conf.quiet_end = None;
+        assert!(!in_quiet_hours(&conf, at(0, 0)));
+        let conf = quiet_conf("late", "07:00", "UTC");
+        assert!(!in_quiet_hours(&conf, at(0, 0)));
+    }
+
+    #[test]
+    fn digests() {
+        let notifications = [
+            notification("One", Some("https://example.com/1"), 2),
+            notification("Two", None, 5),
+        ];
+        let markdown = digest(&notifications, Format::Markdown);
+        assert_eq!(markdown.title, "2 notifications during quiet hours");
+        assert_eq!(markdown.message, "- [One](https://example.com/1)\n- Two");
+        assert_eq!(markdown.link, None);
+        assert_eq!(markdown.priority, 5);
+
+        let html = digest(&notifications, Format::Html);
+        assert_eq!(
+            html.message,
+            "- <a href=\"https://example.com/1\">One</a><br>\n- Two"
+        );
+
+        let plain = digest(&notifications, Format::Plain);
+        assert_eq!(plain.message, "- One (https://example.com/1)\n- Two");
+    }
+
+    #[test]
+    fn seen_entries() {
+        let seen = |hash: &str, updated: Option<i64>| Some((hash.to_owned(), updated));
+        assert_eq!(classify("a", None, None), Seen::New);
+        assert_eq!(classify("a", Some(1), None), Seen::New);
+        assert_eq!(classify("a", None, seen("a", None)), Seen::Unchanged);
+        assert_eq!(classify("a", Some(1), seen("a", Some(1))), Seen::Unchanged);
+        // Either a different content or a different updated time is an update
+        assert_eq!(classify("b", Some(1), seen("a", Some(1))), Seen::Updated);
+        assert_eq!(classify("a", Some(2), seen("a", Some(1))), Seen::Updated);
+        assert_eq!(classify("a", Some(1), seen("a", None)), Seen::Updated);
+        assert_eq!(classify("a", None, seen("a", Some(1))), Seen::Updated);
+    }
+
+    fn entry(title: &str, description: &str) -> model::Entry {
+        let xml = format!(
+            "<rss version=\"2.0\"><channel><title>Feed</title><item>\
+             <title>{}</title><description>{}</description>\
+             </item></channel></rss>",
+            title, description
+        );
+        let feed = feed_rs::parser::parse(xml.as_bytes()).unwrap();
+        return feed.entries[0].clone();
+    }
+
+    #[test]
+    fn entry_hashes() {
+        let hash = rss_utils::entry_hash(&entry("Title", "Summary"));
+        assert_eq!(hash, rss_utils::entry_hash(&entry("Title", "Summary")));
+        assert_ne!(hash, rss_utils::entry_hash(&entry("Title", "Changed")));
+        assert_ne!(hash, rss_utils::entry_hash(&entry("Changed", "Summary")));
+        // The fields are separated, so moving text between them counts
+        assert_ne!(
+            rss_utils::entry_hash(&entry("ab", "")),
+            rss_utils::entry_hash(&entry("a", "b"))
+        );
+
+        // Only the content is hashed, not the updated time
+        let mut updated = entry("Title", "Summary");
+        updated.updated = Some(Utc::now());
+        assert_eq!(hash, rss_utils::entry_hash(&updated));
+    }
+
+    #[test]
+    fn overflow_summary() {
+        assert_eq!(overflow_entries(1, 0), "1 more new entry");
+        assert_eq!(overflow_entries(3, 0), "3 more new entries");
+        assert_eq!(overflow_entries(0, 2), "2 more updated entries");
+        assert_eq!(
+            overflow_entries(2, 1),
+            "2 more new and 1 more updated entries"
+        );
+    }
+
+    #[test]
+    fn digest_is_raw_text() {
+        // Queued notifications are stored as is, the digest must not escape
+        // them for json, that happens when the payload is built
+        let notifications = [notification("Say \"hi\"", None, 1)];
+        let res = digest(&notifications, Format::Plain);
+        assert_eq!(res.message, "- Say \"hi\"");
+        let payload: serde_json::Value =
+            serde_json::from_str(&gotify_payload(&res, &FeedConf::default())).unwrap();
+        assert_eq!(payload["message"], "- Say \"hi\"");
+    }
+}
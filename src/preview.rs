@@ -0,0 +1,114 @@
+use crate::database::{self, FeedConf, Format};
+use crate::notify::{self, Notification};
+use crate::rss_utils;
+
+use feed_rs::model::Feed;
+use log::error;
+use std::cmp::Reverse;
+
+/**
+ * Render the templates for the latest `count` entries of a feed, returns the
+ * id of every entry with its notification.
+ */
+pub fn render_latest(
+    feed: &mut Feed,
+    feed_conf: &FeedConf,
+    count: usize,
+) -> Vec<(String, Notification)> {
+    feed.entries
+        .sort_by_key(|x| Reverse(x.published.or(x.updated)));
+    feed.entries.truncate(count);
+    return feed
+        .entries
+        .iter()
+        .map(|entry| {
+            let notification =
+                notify::render(&feed_conf.title, &feed_conf.message, entry, feed, feed_conf);
+            (entry.id.to_owned(), notification)
+        })
+        .collect();
+}
+
+/**
+ * Render the templates for the latest `count` entries of a feed and print
+ * the payload that would be sent to gotify. Nothing is sent, and nothing is
+ * written to the database.
+ *
+ * The feed config is taken from the database if `feed_id` is given, otherwise
+ * the defaults are used. `title` and `message` override the templates,
+ * `format` and `max_length` the output format and maximum length.
+ */
+pub async fn run(
+    source: &str,
+    feed_id: Option<u32>,
+    title: Option<String>,
+    message: Option<String>,
+    format: Option<String>,
+    max_length: Option<u32>,
+    count: usize,
+) -> bool {
+    let mut feed_conf;
+    match feed_id {
+        Some(id) => {
+            let res_conn = database::new_conn();
+            if let None = res_conn {
+                return false;
+            }
+            let mut conn = res_conn.unwrap();
+            let res_feeds = database::get_feeds(&mut conn);
+            if let None = res_feeds {
+                return false;
+            }
+            match res_feeds.unwrap().into_iter().find(|x| x.id == id) {
+                Some(x) => feed_conf = x,
+                None => {
+                    error!("There is no feed with id {}", id);
+                    return false;
+                }
+            }
+        }
+        None => {
+            feed_conf = FeedConf::default();
+            feed_conf.url = source.to_owned();
+        }
+    }
+    if let Some(x) = title {
+        feed_conf.title = x;
+    }
+    if let Some(x) = message {
+        feed_conf.message = x;
+    }
+    if let Some(x) = format {
+        match Format::parse(&x) {
+            Some(x) => feed_conf.format = x,
+            None => {
+                error!("Invalid format {:?}, use markdown, html or plain", x);
+                return false;
+            }
+        }
+    }
+    if let Some(x) = max_length {
+        feed_conf.max_length = Some(x);
+    }
+
+    if let Err(e) = rss_utils::validate_templates(&feed_conf) {
+        error!("{}", e);
+        return false;
+    }
+
+    let mut feed;
+    match rss_utils::load_feed(source).await {
+        Ok(x) => feed = x,
+        Err(e) => {
+            error!("Could not load feed ({:?})", e);
+            return false;
+        }
+    }
+    let notifications = render_latest(&mut feed, &feed_conf, count);
+    for (i, (entry_id, notification)) in notifications.iter().enumerate() {
+        println!("--- Entry {}/{} ({})", i + 1, notifications.len(), entry_id);
+        println!("POST {}/message", feed_conf.push_url);
+        println!("{}", notify::gotify_payload(notification, &feed_conf));
+    }
+    return true;
+}
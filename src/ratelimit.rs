@@ -0,0 +1,238 @@
+use crate::database::FeedConf;
+
+use chrono::prelude::{DateTime, Utc};
+use chrono::Duration;
+use log::{debug, error};
+use std::collections::HashMap;
+use std::env;
+use std::process;
+
+/**
+ * Keeps track of how many notifications have been sent per feed and per
+ * notification target, so we can stop a single misbehaving feed from flooding
+ * gotify. This only lives in memory, so the history is lost on restart.
+ */
+pub struct RateLimiter {
+    default_target_limit: Option<u32>,
+    feed_history: HashMap<u32, Vec<DateTime<Utc>>>,
+    target_history: HashMap<String, Vec<DateTime<Utc>>>,
+}
+
+/**
+ * The key we use to identify a notification target.
+ */
+fn target_key(feed_conf: &FeedConf) -> String {
+    return format!("{}#{}", feed_conf.push_url, feed_conf.push_token);
+}
+
+/**
+ * Remove all timestamps older than `max_age` from the history.
+ */
+fn prune(history: &mut Vec<DateTime<Utc>>, now: DateTime<Utc>, max_age: Duration) {
+    history.retain(|x| now - *x < max_age);
+}
+
+impl RateLimiter {
+    /**
+     * Create a rate limiter, `default_target_limit` is used for targets
+     * without a limit of their own (notifications per minute).
+     */
+    pub fn new(default_target_limit: Option<u32>) -> RateLimiter {
+        return RateLimiter {
+            default_target_limit,
+            feed_history: HashMap::new(),
+            target_history: HashMap::new(),
+        };
+    }
+
+    /**
+     * Create a rate limiter, the default per target limit is read from
+     * $TARGET_RATE_LIMIT (notifications per minute).
+     */
+    pub fn from_env() -> RateLimiter {
+        let target_limit;
+        match env::var("TARGET_RATE_LIMIT") {
+            Ok(val) => {
+                let res = val.parse::<u32>();
+                if let Err(_e) = res {
+                    error!("Invalid $TARGET_RATE_LIMIT value {:#?}", val);
+                    process::exit(1);
+                }
+                target_limit = Some(res.unwrap());
+            }
+            Err(_e) => target_limit = None,
+        }
+        return RateLimiter::new(target_limit);
+    }
+
+    /**
+     * The per minute limit of the target the feed sends to, if any.
+     */
+    fn target_limit(&self, feed_conf: &FeedConf) -> Option<u32> {
+        return feed_conf.target_rate_limit.or(self.default_target_limit);
+    }
+
+    /**
+     * Check wether we are allowed to send another notification for this feed
+     * now. `sent_this_cycle` is the number of notifications already sent for
+     * the feed in the current iteration.
+     */
+    pub fn allow(
+        &mut self,
+        feed_conf: &FeedConf,
+        sent_this_cycle: u32,
+        now: DateTime<Utc>,
+    ) -> bool {
+        if let Some(limit) = feed_conf.rate_limit_cycle {
+            if sent_this_cycle >= limit {
+                debug!("Feed {} hit its limit of {} per cycle", feed_conf.id, limit);
+                return false;
+            }
+        }
+
+        if let Some(limit) = feed_conf.rate_limit_hour {
+            let history = self.feed_history.entry(feed_conf.id).or_default();
+            prune(history, now, Duration::hours(1));
+            if history.len() >= limit as usize {
+                debug!("Feed {} hit its limit of {} per hour", feed_conf.id, limit);
+                return false;
+            }
+        }
+
+        if let Some(limit) = self.target_limit(feed_conf) {
+            let history = self
+                .target_history
+                .entry(target_key(feed_conf))
+                .or_default();
+            prune(history, now, Duration::minutes(1));
+            if history.len() >= limit as usize {
+                debug!(
+                    "Target {} hit its limit of {} per minute",
+                    feed_conf.push_url, limit
+                );
+                return false;
+            }
+        }
+        return true;
+    }
+
+    /**
+     * Record that a notification was sent for this feed. Only the limits that
+     * are set keep a history, and it is pruned here as well, so it does not
+     * grow for feeds and targets that are never checked by allow.
+     */
+    pub fn record(&mut self, feed_conf: &FeedConf, now: DateTime<Utc>) {
+        if feed_conf.rate_limit_hour.is_some() {
+            let history = self.feed_history.entry(feed_conf.id).or_default();
+            prune(history, now, Duration::hours(1));
+            history.push(now);
+        }
+        if self.target_limit(feed_conf).is_some() {
+            let history = self
+                .target_history
+                .entry(target_key(feed_conf))
+                .or_default();
+            prune(history, now, Duration::minutes(1));
+            history.push(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(minute: u32, second: u32) -> DateTime<Utc> {
+        return Utc.with_ymd_and_hms(2024, 1, 2, 3, minute, second).unwrap();
+    }
+
+    fn conf(id: u32, push_url: &str) -> FeedConf {
+        return FeedConf {
+            id,
+            push_url: push_url.to_owned(),
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn no_limits() {
+        let mut limiter = RateLimiter::new(None);
+        let feed = conf(1, "https://push.example.com");
+        for i in 0..100 {
+            assert!(limiter.allow(&feed, i, at(0, 0)));
+            limiter.record(&feed, at(0, 0));
+        }
+        // Nothing is kept when there is nothing to limit
+        assert!(limiter.feed_history.values().all(|x| x.is_empty()));
+        assert!(limiter.target_history.values().all(|x| x.is_empty()));
+    }
+
+    #[test]
+    fn limit_per_cycle() {
+        let mut limiter = RateLimiter::new(None);
+        let mut feed = conf(1, "https://push.example.com");
+        feed.rate_limit_cycle = Some(2);
+        assert!(limiter.allow(&feed, 0, at(0, 0)));
+        assert!(limiter.allow(&feed, 1, at(0, 0)));
+        assert!(!limiter.allow(&feed, 2, at(0, 0)));
+    }
+
+    #[test]
+    fn limit_per_hour() {
+        let mut limiter = RateLimiter::new(None);
+        let mut feed = conf(1, "https://push.example.com");
+        feed.rate_limit_hour = Some(2);
+        limiter.record(&feed, at(0, 0));
+        limiter.record(&feed, at(30, 0));
+        assert!(!limiter.allow(&feed, 0, at(59, 59)));
+        // An hour after the first one there is room for one more
+        let later = at(0, 0) + Duration::hours(1);
+        assert!(limiter.allow(&feed, 0, later));
+        limiter.record(&feed, later);
+        assert!(!limiter.allow(&feed, 0, later));
+        // Other feeds have their own history
+        let mut other = conf(2, "https://push.example.com");
+        other.rate_limit_hour = Some(2);
+        assert!(limiter.allow(&other, 0, later));
+    }
+
+    #[test]
+    fn limit_per_target() {
+        let mut limiter = RateLimiter::new(Some(1));
+        let feed = conf(1, "https://push.example.com");
+        let same_target = conf(2, "https://push.example.com");
+        let other_target = conf(3, "https://other.example.com");
+        limiter.record(&feed, at(0, 0));
+        assert!(!limiter.allow(&same_target, 0, at(0, 59)));
+        assert!(limiter.allow(&other_target, 0, at(0, 59)));
+        assert!(limiter.allow(&same_target, 0, at(1, 0)));
+
+        // The limit of a target wins over the default
+        let mut limited = conf(4, "https://limited.example.com");
+        limited.target_rate_limit = Some(2);
+        limiter.record(&limited, at(0, 0));
+        assert!(limiter.allow(&limited, 0, at(0, 0)));
+        limiter.record(&limited, at(0, 0));
+        assert!(!limiter.allow(&limited, 0, at(0, 0)));
+    }
+
+    #[test]
+    fn record_prunes() {
+        let mut limiter = RateLimiter::new(Some(10));
+        let mut feed = conf(1, "https://push.example.com");
+        feed.rate_limit_hour = Some(10);
+        for i in 0..5 {
+            limiter.record(&feed, at(0, 0) + Duration::hours(i));
+        }
+        assert_eq!(limiter.feed_history[&1].len(), 1);
+        assert_eq!(limiter.target_history[&target_key(&feed)].len(), 1);
+    }
+
+    #[test]
+    fn prunes_old_timestamps() {
+        let mut history = vec![at(0, 0), at(0, 30), at(1, 0)];
+        prune(&mut history, at(1, 30), Duration::minutes(1));
+        assert_eq!(history, vec![at(1, 0)]);
+    }
+}
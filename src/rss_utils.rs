@@ -1,211 +1,899 @@
-use crate::database::FeedConf;
+use crate::database::{FeedConf, Format};
+use crate::metrics;
+use crate::template::{self, RenderOptions, Value};
+
+use ammonia::{Url, UrlRelative};
 
 use chrono::prelude::{DateTime, Utc};
 use feed_rs::model;
 use feed_rs::parser;
-use html2md;
-use log::{debug, info};
+use html2md::{Handle, StructuredPrinter, TagHandler, TagHandlerFactory};
+use log::{debug, error, info};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 extern crate mime;
 
+/**
+ * Escape the characters that have a special meaning in HTML.
+ */
+pub fn escape_html(input: &str) -> String {
+    return input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+/**
+ * How text from the feed is converted, this is the output format and the
+ * sanitization policy of the feed. Relative URLs are resolved against `base`.
+ */
+struct TextOptions {
+    format: Format,
+    strip_images: bool,
+    strip_iframes: bool,
+    collapse_whitespace: bool,
+    base: Option<Url>,
+}
+
+impl TextOptions {
+    fn new(feed_conf: &FeedConf, feed: &model::Feed) -> TextOptions {
+        let base = feed
+            .links
+            .iter()
+            .find(|x| x.rel.is_none() || x.rel.as_deref() == Some("alternate"))
+            .or_else(|| feed.links.first())
+            .and_then(|x| Url::parse(&x.href).ok());
+        return TextOptions {
+            format: feed_conf.format,
+            strip_images: feed_conf.strip_images,
+            strip_iframes: feed_conf.strip_iframes,
+            collapse_whitespace: feed_conf.collapse_whitespace,
+            base,
+        };
+    }
+}
+
+/**
+ * Ammonia adds this attribute to every image after all the other ones, so the
+ * attribute filter knows where an image ends. The filter drops it again.
+ */
+const IMAGE_END: &str = "data-image-end";
+
+/**
+ * What the attribute filter of sanitize_html knows about the images. The
+ * filter only sees one attribute at a time, so the first pass finds the
+ * tracking pixels (images that are 0 or 1 pixel wide or high) and the second
+ * pass drops all attributes of those. Unless `keep_size` is set, the size of
+ * the other images is dropped too, as html2md keeps images with a size as HTML.
+ */
+#[derive(Default)]
+struct ImageFilter {
+    keep_size: bool,
+    pixels: Vec<bool>,
+    is_pixel: bool,
+    second_pass: bool,
+    current: usize,
+}
+
+impl ImageFilter {
+    /**
+     * Check wether to keep an attribute of the current image.
+     */
+    fn keep(&mut self, attribute: &str, value: &str) -> bool {
+        let is_size = attribute == "width" || attribute == "height";
+        if !self.second_pass {
+            if attribute == IMAGE_END {
+                self.pixels.push(self.is_pixel);
+                self.is_pixel = false;
+            } else if is_size && (value == "0" || value == "1") {
+                self.is_pixel = true;
+            }
+            return true;
+        }
+        if attribute == IMAGE_END {
+            self.current += 1;
+            return false;
+        }
+        if self.pixels.get(self.current) == Some(&true) {
+            return false;
+        }
+        return !is_size || self.keep_size;
+    }
+}
+
+/**
+ * Converts images like html2md does, but leaves out images without a source,
+ * such as the tracking pixels sanitize_html emptied.
+ */
+#[derive(Default)]
+struct ImageHandler {
+    inner: html2md::images::ImgHandler,
+    skip: bool,
+}
+
+impl TagHandler for ImageHandler {
+    fn handle(&mut self, tag: &Handle, printer: &mut StructuredPrinter) {
+        self.skip = html2md::common::get_tag_attr(tag, "src").is_none();
+        if !self.skip {
+            self.inner.handle(tag, printer);
+        }
+    }
+
+    fn after_handle(&mut self, printer: &mut StructuredPrinter) {
+        if !self.skip {
+            self.inner.after_handle(printer);
+        }
+    }
+}
+
+struct ImageHandlerFactory;
+
+impl TagHandlerFactory for ImageHandlerFactory {
+    fn instantiate(&self) -> Box<dyn TagHandler> {
+        return Box::<ImageHandler>::default();
+    }
+}
+
+/**
+ * Convert sanitized HTML to markdown.
+ */
+fn html_to_markdown(html: &str) -> String {
+    let mut handlers: HashMap<String, Box<dyn TagHandlerFactory>> = HashMap::new();
+    handlers.insert("img".to_owned(), Box::new(ImageHandlerFactory));
+    return html2md::parse_html_custom(html, &handlers);
+}
+
+/**
+ * Sanitize HTML from the feed. Scripts, styles and unsafe attributes are
+ * always removed, images and iframes depending on the feed config. Relative
+ * URLs are made absolute. Tracking pixels are left without any attributes, so
+ * they load nothing.
+ */
+fn sanitize_html(content: &str, opts: &TextOptions) -> String {
+    let mut builder = ammonia::Builder::default();
+    if opts.strip_images {
+        builder.rm_tags(["img"]);
+    }
+    if !opts.strip_iframes {
+        builder.add_tags(["iframe"]);
+        builder.add_tag_attributes("iframe", ["src", "width", "height"]);
+    }
+    if let Some(base) = &opts.base {
+        builder.url_relative(UrlRelative::RewriteWithBase(base.clone()));
+    }
+    let images = Arc::new(Mutex::new(ImageFilter {
+        keep_size: opts.format != Format::Markdown,
+        ..Default::default()
+    }));
+    let filter = images.clone();
+    builder.set_tag_attribute_value("img", IMAGE_END, "");
+    builder.attribute_filter(move |element, attribute, value| {
+        if element == "img" && !filter.lock().unwrap().keep(attribute, value) {
+            return None;
+        }
+        return Some(value.into());
+    });
+    // The first pass only finds the tracking pixels
+    builder.clean(content);
+    images.lock().unwrap().second_pass = true;
+    return builder.clean(content).to_string();
+}
+
+/**
+ * Collapse runs of spaces within lines and runs of empty lines, leading
+ * indentation is kept, and so are markdown line breaks (two trailing spaces).
+ */
+fn collapse_whitespace(text: &str, format: Format) -> String {
+    let mut out = "".to_owned();
+    let mut blank = false;
+    for line in text.lines() {
+        let content = line.trim();
+        if content.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if !out.is_empty() {
+            out.push('\n');
+            if blank {
+                out.push('\n');
+            }
+        }
+        blank = false;
+        out.push_str(&line[..line.len() - line.trim_start().len()]);
+        out.push_str(&content.split_whitespace().collect::<Vec<&str>>().join(" "));
+        if format == Format::Markdown && line.ends_with("  ") {
+            out.push_str("  ");
+        }
+    }
+    return out;
+}
+
+/**
+ * Convert text with the given content type to the output format. HTML and
+ * XHTML is sanitized and converted to markdown or stripped to plain text,
+ * plain text is used as is (escaped for HTML output).
+ */
+fn convert_text(content: &str, content_type: &mime::Mime, opts: &TextOptions) -> String {
+    let is_html = matches!(
+        (content_type.type_(), content_type.subtype().as_str()),
+        (mime::TEXT, "html") | (_, "xhtml") | (_, "xhtml+xml")
+    );
+    let text = match (opts.format, is_html) {
+        (Format::Markdown, true) => html_to_markdown(&sanitize_html(content, opts)),
+        (Format::Html, true) => sanitize_html(content, opts),
+        (Format::Plain, true) => template::strip_html(&sanitize_html(content, opts)),
+        (Format::Html, false) => escape_html(content),
+        _ => content.to_owned(),
+    };
+    if opts.collapse_whitespace {
+        return collapse_whitespace(&text, opts.format);
+    }
+    return text;
+}
+
 /**
  * Extract text field from Option
  */
-fn extract_text(text: &Option<model::Text>, field: &str) -> String {
+fn extract_text(text: &Option<model::Text>, opts: &TextOptions) -> Value {
     if text.is_none() {
-        return String::from(format!("Field {:#?} was not in feed", field));
+        return Value::None;
     }
     let field = text.as_ref().unwrap();
-    match (field.content_type.type_(), field.content_type.subtype()) {
-        (mime::TEXT, mime::HTML) => return html2md::parse_html(field.content.as_ref()),
-        (mime::TEXT, mime::PLAIN) => return field.content.to_owned(),
-        _ => {
-            return String::from(format!(
-                "Unknown field content type {:#?}",
-                field.content_type
-            ))
-        }
+    return Value::Text(convert_text(&field.content, &field.content_type, opts));
+}
+
+/**
+ * Extract the content of an entry, if the content is not inline we use the
+ * link to it.
+ */
+fn extract_content(content: &Option<model::Content>, opts: &TextOptions) -> Value {
+    let content = match content {
+        Some(x) => x,
+        None => return Value::None,
+    };
+    if let Some(body) = &content.body {
+        return Value::Text(convert_text(body, &content.content_type, opts));
+    }
+    if let Some(src) = &content.src {
+        return Value::Text(src.href.to_owned());
+    }
+    return Value::None;
+}
+
+/**
+ * Extract number field from Option
+ */
+fn extract_number<T: ToString>(number: &Option<T>) -> Value {
+    match number {
+        Some(x) => return Value::Text(x.to_string()),
+        None => return Value::None,
     }
 }
 
+/**
+ * Format a duration as `H:MM:SS`, or `M:SS` if it is shorter than an hour.
+ */
+fn extract_duration(duration: &Option<std::time::Duration>) -> Value {
+    let secs = match duration {
+        Some(x) => x.as_secs(),
+        None => return Value::None,
+    };
+    if secs >= 3600 {
+        return Value::Text(format!(
+            "{}:{:02}:{:02}",
+            secs / 3600,
+            (secs % 3600) / 60,
+            secs % 60
+        ));
+    }
+    return Value::Text(format!("{}:{:02}", secs / 60, secs % 60));
+}
+
+/**
+ * Turn a feed_rs::model::Image into a template object with the fields url,
+ * title, width and height. It is displayed as its url.
+ */
+fn image_value(image: &Option<model::Image>) -> Value {
+    let image = match image {
+        Some(x) => x,
+        None => return Value::None,
+    };
+    let mut fields = BTreeMap::new();
+    fields.insert("url".to_owned(), Value::Text(image.uri.to_owned()));
+    fields.insert("title".to_owned(), extract_string(&image.title));
+    fields.insert("width".to_owned(), extract_number(&image.width));
+    fields.insert("height".to_owned(), extract_number(&image.height));
+    return Value::Object(fields, image.uri.to_owned());
+}
+
 /**
  * Extract string field from Option
  */
-fn extract_string(text: &Option<String>, field: &str) -> String {
+fn extract_string(text: &Option<String>) -> Value {
     if text.is_none() {
-        return String::from(format!("Field {:#?} was not in feed", field));
+        return Value::None;
     }
-    return text.as_ref().unwrap().to_owned();
+    return Value::Text(text.as_ref().unwrap().to_owned());
 }
 
 /**
- * Extract string field from Option
+ * Extract datetime field from Option
  */
-fn extract_datetime(date: &Option<DateTime<Utc>>, field: &str) -> String {
+fn extract_datetime(date: &Option<DateTime<Utc>>) -> Value {
     if date.is_none() {
-        return String::from(format!("Field {:#?} was not in feed", field));
+        return Value::None;
     }
-    return date.unwrap().to_rfc2822().replace("+0000", "UTC");
+    return Value::Date(date.unwrap());
 }
 
 /**
- * Turn a vector of feed_rs::model::Person into markdown.
+ * Format a link in the output format.
  */
-fn person_vec_to_md(person_vec: &Vec<model::Person>) -> String {
-    let mut md_str = "".to_owned();
+fn link_display(text: &str, href: &str, format: Format) -> String {
+    match format {
+        Format::Markdown => return format!("[{}]({})", text, href),
+        Format::Html => {
+            return format!(
+                "<a href=\"{}\">{}</a>",
+                escape_html(href),
+                escape_html(text)
+            )
+        }
+        Format::Plain if text == href => return href.to_owned(),
+        Format::Plain => return format!("{} ({})", text, href),
+    }
+}
+
+/**
+ * Turn a vector of feed_rs::model::Person into a string in the output format.
+ */
+fn person_vec_display(person_vec: &[model::Person], format: Format) -> String {
+    let mut display = "".to_owned();
 
     for (i, person) in person_vec.iter().enumerate() {
         if person.uri.is_some() && person.email.is_some() {
-            md_str.push_str(
+            display.push_str(
                 format!(
-                    "[{}]({}) - [homepage]({})",
-                    person.name,
-                    person.email.as_ref().unwrap(),
-                    person.uri.as_ref().unwrap()
+                    "{} - {}",
+                    link_display(&person.name, person.email.as_ref().unwrap(), format),
+                    link_display("homepage", person.uri.as_ref().unwrap(), format)
                 )
                 .as_str(),
             );
         } else if person.uri.is_some() {
-            md_str
-                .push_str(format!("[{}]({})", person.name, person.uri.as_ref().unwrap(),).as_str());
+            display.push_str(&link_display(
+                &person.name,
+                person.uri.as_ref().unwrap(),
+                format,
+            ));
         } else if person.email.is_some() {
-            md_str.push_str(
-                format!("[{}]({})", person.name, person.email.as_ref().unwrap(),).as_str(),
-            );
+            display.push_str(&link_display(
+                &person.name,
+                person.email.as_ref().unwrap(),
+                format,
+            ));
+        } else if format == Format::Html {
+            display.push_str(&escape_html(&person.name));
         } else {
-            md_str.push_str(&person.name);
+            display.push_str(&person.name);
         }
         if i < (person_vec.len() - 1) {
-            md_str.push_str(", ");
+            display.push_str(", ");
         }
     }
-    return md_str;
+    return display;
 }
 
 /**
- * Turn a vector of feed_rs::model::Link into markdown.
+ * Turn a vector of feed_rs::model::Link into a string in the output format.
  */
-fn link_vec_to_md(link_vec: &Vec<model::Link>) -> String {
-    let mut md_str = "".to_owned();
+fn link_vec_display(link_vec: &[model::Link], format: Format) -> String {
+    let mut display = "".to_owned();
 
     for (i, link) in link_vec.iter().enumerate() {
         if link.title.is_some() {
-            md_str
-                .push_str(format!("[{}]({})", &link.title.as_ref().unwrap(), &link.href).as_str());
+            display.push_str(&link_display(
+                link.title.as_ref().unwrap(),
+                &link.href,
+                format,
+            ));
         } else if link.rel.is_some() {
-            md_str.push_str(format!("[{}]({})", &link.rel.as_ref().unwrap(), &link.href).as_str());
+            display.push_str(&link_display(
+                link.rel.as_ref().unwrap(),
+                &link.href,
+                format,
+            ));
         } else {
-            md_str.push_str(format!("[{}]({})", &link.href, &link.href).as_str());
+            display.push_str(&link_display(&link.href, &link.href, format));
         }
         if i < (link_vec.len() - 1) {
-            md_str.push_str(", ");
+            display.push_str(", ");
         }
     }
-    return md_str;
+    return display;
 }
 
 /**
- * Turn a vector of feed_rs::model::Category into markdown.
+ * Turn a vector of feed_rs::model::Category into a string in the output
+ * format.
  */
-fn category_vec_to_md(category_vec: &Vec<model::Category>) -> String {
-    let mut md_str = "".to_owned();
+fn category_vec_display(category_vec: &[model::Category], format: Format) -> String {
+    let mut display = "".to_owned();
 
     for (i, category) in category_vec.iter().enumerate() {
-        if category.label.is_some() {
-            md_str.push_str(category.label.as_ref().unwrap());
+        let name = category.label.as_ref().unwrap_or(&category.term);
+        if format == Format::Html {
+            display.push_str(&escape_html(name));
         } else {
-            md_str.push_str(&category.term);
+            display.push_str(name);
         }
         if i < (category_vec.len() - 1) {
-            md_str.push_str(", ");
+            display.push_str(", ");
         }
     }
-    return md_str;
+    return display;
+}
+
+/**
+ * Turn a vector of feed_rs::model::Person into a template list, where every
+ * person has the fields name, uri and email.
+ */
+fn person_vec_value(person_vec: &[model::Person], format: Format) -> Value {
+    let items = person_vec
+        .iter()
+        .map(|person| {
+            let mut fields = BTreeMap::new();
+            fields.insert("name".to_owned(), Value::Text(person.name.to_owned()));
+            fields.insert("uri".to_owned(), extract_string(&person.uri));
+            fields.insert("email".to_owned(), extract_string(&person.email));
+            Value::Object(
+                fields,
+                person_vec_display(std::slice::from_ref(person), format),
+            )
+        })
+        .collect();
+    return Value::List(items, person_vec_display(person_vec, format));
+}
+
+/**
+ * Turn a vector of feed_rs::model::Link into a template list, where every
+ * link has the fields href, title, rel and media_type.
+ */
+fn link_vec_value(link_vec: &[model::Link], format: Format) -> Value {
+    let items = link_vec
+        .iter()
+        .map(|link| {
+            let mut fields = BTreeMap::new();
+            fields.insert("href".to_owned(), Value::Text(link.href.to_owned()));
+            fields.insert("title".to_owned(), extract_string(&link.title));
+            fields.insert("rel".to_owned(), extract_string(&link.rel));
+            fields.insert("media_type".to_owned(), extract_string(&link.media_type));
+            Value::Object(fields, link_vec_display(std::slice::from_ref(link), format))
+        })
+        .collect();
+    return Value::List(items, link_vec_display(link_vec, format));
+}
+
+/**
+ * Turn a vector of feed_rs::model::Category into a template list, where every
+ * category has the fields term, label and scheme.
+ */
+fn category_vec_value(category_vec: &[model::Category], format: Format) -> Value {
+    let items = category_vec
+        .iter()
+        .map(|category| {
+            let mut fields = BTreeMap::new();
+            fields.insert("term".to_owned(), Value::Text(category.term.to_owned()));
+            fields.insert("label".to_owned(), extract_string(&category.label));
+            fields.insert("scheme".to_owned(), extract_string(&category.scheme));
+            Value::Object(
+                fields,
+                category_vec_display(std::slice::from_ref(category), format),
+            )
+        })
+        .collect();
+    return Value::List(items, category_vec_display(category_vec, format));
+}
+
+/**
+ * Turn a vector of feed_rs::model::MediaObject into a template list. Every
+ * media object has the fields title, description, duration, url, type,
+ * thumbnail and the lists thumbnails and content. `url`/`type` are from the
+ * first content and `thumbnail` is the url of the first thumbnail.
+ */
+fn media_vec_value(media_vec: &[model::MediaObject], opts: &TextOptions) -> Value {
+    let items: Vec<Value> = media_vec
+        .iter()
+        .map(|media| {
+            let thumbnails: Vec<Value> = media
+                .thumbnails
+                .iter()
+                .map(|x| image_value(&Some(x.image.clone())))
+                .collect();
+            let content: Vec<Value> = media
+                .content
+                .iter()
+                .map(|x| {
+                    let url = x.url.as_ref().map(|x| x.to_string());
+                    let mut fields = BTreeMap::new();
+                    fields.insert("url".to_owned(), extract_string(&url));
+                    fields.insert("type".to_owned(), extract_number(&x.content_type.as_ref()));
+                    fields.insert("width".to_owned(), extract_number(&x.width));
+                    fields.insert("height".to_owned(), extract_number(&x.height));
+                    fields.insert("size".to_owned(), extract_number(&x.size));
+                    fields.insert("duration".to_owned(), extract_duration(&x.duration));
+                    Value::Object(fields, url.unwrap_or_default())
+                })
+                .collect();
+
+            let first_content = media.content.first();
+            let url = first_content.and_then(|x| x.url.as_ref().map(|x| x.to_string()));
+            let duration = media
+                .duration
+                .or_else(|| first_content.and_then(|x| x.duration));
+            let thumbnail = thumbnails.first().cloned().unwrap_or(Value::None);
+
+            let mut fields = BTreeMap::new();
+            fields.insert("title".to_owned(), extract_text(&media.title, opts));
+            fields.insert(
+                "description".to_owned(),
+                extract_text(&media.description, opts),
+            );
+            fields.insert("duration".to_owned(), extract_duration(&duration));
+            fields.insert("url".to_owned(), extract_string(&url));
+            fields.insert(
+                "type".to_owned(),
+                extract_number(&first_content.and_then(|x| x.content_type.as_ref())),
+            );
+            fields.insert("thumbnail".to_owned(), thumbnail);
+            let thumbnails_md = thumbnails
+                .iter()
+                .map(|x| x.display())
+                .collect::<Vec<String>>()
+                .join(", ");
+            fields.insert(
+                "thumbnails".to_owned(),
+                Value::List(thumbnails, thumbnails_md),
+            );
+            let content_md = content
+                .iter()
+                .map(|x| x.display())
+                .collect::<Vec<String>>()
+                .join(", ");
+            fields.insert("content".to_owned(), Value::List(content, content_md));
+            Value::Object(fields, url.unwrap_or_default())
+        })
+        .collect();
+    let md = items
+        .iter()
+        .map(|x| x.display())
+        .collect::<Vec<String>>()
+        .join(", ");
+    return Value::List(items, md);
+}
+
+/**
+ * The main link of an entry, that is the first alternate link, or the first
+ * link if there are no alternate links.
+ */
+fn entry_link(entry: &model::Entry) -> Option<&model::Link> {
+    let alternate = entry
+        .links
+        .iter()
+        .find(|x| x.rel.is_none() || x.rel.as_deref() == Some("alternate"));
+    return alternate.or_else(|| entry.links.first());
+}
+
+/**
+ * The enclosure of an entry with the fields url, type and length. Atom feeds
+ * have these as links with rel="enclosure", while feed_rs turns RSS
+ * enclosures into media objects.
+ */
+fn enclosure_value(entry: &model::Entry) -> Value {
+    let mut fields = BTreeMap::new();
+    let link = entry
+        .links
+        .iter()
+        .find(|x| x.rel.as_deref() == Some("enclosure"));
+    if let Some(link) = link {
+        fields.insert("url".to_owned(), Value::Text(link.href.to_owned()));
+        fields.insert("type".to_owned(), extract_string(&link.media_type));
+        fields.insert("length".to_owned(), extract_number(&link.length));
+        return Value::Object(fields, link.href.to_owned());
+    }
+    let content = entry
+        .media
+        .iter()
+        .flat_map(|x| x.content.iter())
+        .find(|x| x.url.is_some());
+    if let Some(content) = content {
+        let url = content.url.as_ref().unwrap().to_string();
+        fields.insert("url".to_owned(), Value::Text(url.to_owned()));
+        fields.insert(
+            "type".to_owned(),
+            extract_number(&content.content_type.as_ref()),
+        );
+        fields.insert("length".to_owned(), extract_number(&content.size));
+        return Value::Object(fields, url);
+    }
+    return Value::None;
+}
+
+/**
+ * Build the context templates are rendered with, the feed fields are at the
+ * top level and the entry fields are under `entry`. Text is converted and
+ * sanitized as set in the feed config.
+ */
+pub fn template_context(entry: &model::Entry, feed: &model::Feed, feed_conf: &FeedConf) -> Value {
+    let opts = TextOptions::new(feed_conf, feed);
+    let format = opts.format;
+    let mut e = BTreeMap::new();
+    e.insert("id".to_owned(), Value::Text(entry.id.to_owned()));
+    e.insert("title".to_owned(), extract_text(&entry.title, &opts));
+    e.insert("updated".to_owned(), extract_datetime(&entry.updated));
+    e.insert(
+        "authors".to_owned(),
+        person_vec_value(&entry.authors, format),
+    );
+    e.insert("links".to_owned(), link_vec_value(&entry.links, format));
+    e.insert("summary".to_owned(), extract_text(&entry.summary, &opts));
+    e.insert(
+        "categories".to_owned(),
+        category_vec_value(&entry.categories, format),
+    );
+    e.insert(
+        "contributors".to_owned(),
+        person_vec_value(&entry.contributors, format),
+    );
+    e.insert("published".to_owned(), extract_datetime(&entry.published));
+    e.insert("source".to_owned(), extract_string(&entry.source));
+    e.insert("rights".to_owned(), extract_text(&entry.rights, &opts));
+    e.insert("content".to_owned(), extract_content(&entry.content, &opts));
+    e.insert(
+        "link".to_owned(),
+        extract_string(&entry_link(entry).map(|x| x.href.to_owned())),
+    );
+    e.insert("media".to_owned(), media_vec_value(&entry.media, &opts));
+    e.insert("enclosure".to_owned(), enclosure_value(entry));
+
+    let mut f = BTreeMap::new();
+    f.insert("id".to_owned(), Value::Text(feed.id.to_owned()));
+    f.insert("title".to_owned(), extract_text(&feed.title, &opts));
+    f.insert("updated".to_owned(), extract_datetime(&feed.updated));
+    f.insert(
+        "authors".to_owned(),
+        person_vec_value(&feed.authors, format),
+    );
+    f.insert(
+        "description".to_owned(),
+        extract_text(&feed.description, &opts),
+    );
+    f.insert("links".to_owned(), link_vec_value(&feed.links, format));
+    f.insert(
+        "categories".to_owned(),
+        category_vec_value(&feed.categories, format),
+    );
+    f.insert(
+        "contributors".to_owned(),
+        person_vec_value(&feed.contributors, format),
+    );
+    f.insert("language".to_owned(), extract_string(&feed.language));
+    f.insert("published".to_owned(), extract_datetime(&feed.published));
+    f.insert("rights".to_owned(), extract_text(&feed.rights, &opts));
+    f.insert("icon".to_owned(), image_value(&feed.icon));
+    f.insert("logo".to_owned(), image_value(&feed.logo));
+    // The feed fields are also available under `feed`, e.g. {{feed.icon}}
+    f.insert("feed".to_owned(), Value::Object(f.clone(), "".to_owned()));
+    f.insert("entry".to_owned(), Value::Object(e, "".to_owned()));
+
+    return Value::Object(f, "".to_owned());
 }
 
 /**
  * This will replace a given field with the appropriate formatted string from
- * the rss feed/entry/item.
- */
-fn fill_template_field(field: &str, entry: &model::Entry, feed: &model::Feed) -> String {
-    match field {
-        "id" => return feed.id.to_owned(),
-        "title" => return extract_text(&feed.title, field).to_owned(),
-        "updated" => return extract_datetime(&feed.updated, field).to_owned(),
-        "authors" => return person_vec_to_md(&feed.authors).to_owned(),
-        "description" => return extract_text(&feed.description, field).to_owned(),
-        "links" => return link_vec_to_md(&feed.links).to_owned(),
-        "categories" => return category_vec_to_md(&feed.categories).to_owned(),
-        "contributors" => return person_vec_to_md(&feed.contributors).to_owned(),
-        "language" => return extract_string(&feed.language, field).to_owned(),
-        "published" => return extract_datetime(&feed.published, field).to_owned(),
-        "rights" => return extract_text(&feed.rights, field).to_owned(),
-
-        "entry.id" => return entry.id.to_owned(),
-        "entry.title" => return extract_text(&entry.title, field).to_owned(),
-        "entry.updated" => return extract_datetime(&entry.updated, field).to_owned(),
-        "entry.authors" => return person_vec_to_md(&entry.authors).to_owned(),
-        "entry.links" => return link_vec_to_md(&entry.links).to_owned(),
-        "entry.summary" => return extract_text(&entry.summary, field).to_owned(),
-        "entry.categories" => return category_vec_to_md(&entry.categories).to_owned(),
-        "entry.contributors" => return person_vec_to_md(&entry.contributors).to_owned(),
-        "entry.published" => return extract_datetime(&entry.published, field).to_owned(),
-        "entry.source" => return extract_string(&entry.source, field).to_owned(),
-        "entry.rights" => return extract_text(&entry.rights, field).to_owned(),
-        _ => return String::from(format!("Unknown field {:#?}", field)),
-    }
-}
-
-/**
- * Method that escapes some characters that would break json spec, and also escape
- * special HTML characters.
- */
-pub fn escape(input: String) -> String {
-    return input
-        .replace("\\", "\\\\")
-        .replace("\"", "\\\"")
-        .replace("\n", "\\n")
-        .replace("<", "&lt;")
-        .replace(">", "&gt;")
-        .replace("&", "$amp;");
-}
-
-/**
- * This will find fields in the template string and use fill_template_field
- * to replace the tags with formatted text from the rss feed/entry/item.
- * It does use the escape function on the string it returns.
- */
-pub fn fill_template(template_str: &str, entry: &model::Entry, feed: &model::Feed) -> String {
-    let mut filled_str = "".to_owned();
-
-    let mut l_bracket_n = 0;
-    let mut r_bracket_n = 0;
-    let mut field = "".to_owned();
-
-    for c in template_str.chars() {
-        if l_bracket_n > 1 {
-            if c == '}' {
-                r_bracket_n += 1;
-                if r_bracket_n > 1 {
-                    filled_str.push_str(fill_template_field(&field, &entry, &feed).as_str());
-                    field = "".to_owned();
-                    r_bracket_n = 0;
-                    l_bracket_n = 0;
-                }
-            } else {
-                field.push(c);
-            }
-        } else if c == '{' {
-            l_bracket_n += 1;
-            if l_bracket_n > 1 {
-                field = "".to_owned();
-            }
-        } else {
-            l_bracket_n = 0;
-            filled_str.push(c);
+ * the context of the rss feed/entry/item, see template_context.
+ */
+pub fn fill_template_field(field: &str, ctx: &Value) -> String {
+    match template::lookup(ctx, field) {
+        Some(x) => return x.display(),
+        None => return "".to_owned(),
+    }
+}
+
+/**
+ * The context used to check that templates only use fields that exist. It
+ * is built from an empty feed where every list has one item.
+ */
+fn template_schema() -> Value {
+    let person = model::Person {
+        name: "".to_owned(),
+        uri: None,
+        email: None,
+    };
+    let link = model::Link {
+        href: "".to_owned(),
+        rel: None,
+        media_type: None,
+        href_lang: None,
+        title: None,
+        length: None,
+    };
+    let category = model::Category {
+        term: "".to_owned(),
+        scheme: None,
+        label: None,
+    };
+    let image = model::Image {
+        uri: "".to_owned(),
+        title: None,
+        link: None,
+        width: None,
+        height: None,
+        description: None,
+    };
+    let media = model::MediaObject {
+        content: vec![model::MediaContent {
+            url: None,
+            content_type: None,
+            height: None,
+            width: None,
+            duration: None,
+            size: None,
+            rating: None,
+        }],
+        thumbnails: vec![model::MediaThumbnail {
+            image: image.clone(),
+            time: None,
+        }],
+        ..Default::default()
+    };
+    let mut entry = model::Entry::default();
+    entry.media.push(media);
+    entry.content = Some(model::Content::default());
+    entry.links.push(model::Link {
+        rel: Some("enclosure".to_owned()),
+        ..link.clone()
+    });
+    entry.authors.push(person.clone());
+    entry.contributors.push(person.clone());
+    entry.links.push(link.clone());
+    entry.categories.push(category.clone());
+    let feed = model::Feed {
+        feed_type: model::FeedType::Atom,
+        id: "".to_owned(),
+        title: None,
+        updated: None,
+        authors: vec![person.clone()],
+        description: None,
+        links: vec![link],
+        categories: vec![category],
+        contributors: vec![person],
+        generator: None,
+        icon: Some(image.clone()),
+        language: None,
+        logo: Some(image),
+        published: None,
+        rating: None,
+        rights: None,
+        ttl: None,
+        entries: Vec::new(),
+    };
+    return template_context(&entry, &feed, &FeedConf::default());
+}
+
+/**
+ * Parse a template and make sure it only uses fields that exist.
+ */
+pub fn validate_template(template_str: &str) -> Result<(), String> {
+    let nodes = template::parse(template_str)?;
+    return template::validate(&nodes, &template_schema());
+}
+
+/**
+ * Validate all templates of a feed, returns a description of the first
+ * problem found.
+ */
+pub fn validate_templates(feed_conf: &FeedConf) -> Result<(), String> {
+    let templates = [
+        ("title", &feed_conf.title),
+        ("message", &feed_conf.message),
+        ("update_title", &feed_conf.update_title),
+        ("update_message", &feed_conf.update_message),
+    ];
+    for (name, template_str) in templates {
+        if let Err(e) = validate_template(template_str) {
+            return Err(format!("Invalid {} template: {}", name, e));
+        }
+    }
+    if let Some(format) = &feed_conf.date_format {
+        template::validate_date_format(format)?;
+    }
+    return Ok(());
+}
+
+/**
+ * Hash the title, summary and content of an entry, so we can tell if it has
+ * been changed since we last saw it.
+ */
+pub fn entry_hash(entry: &model::Entry) -> String {
+    let mut hasher = Sha256::new();
+    if let Some(title) = &entry.title {
+        hasher.update(title.content.as_bytes());
+    }
+    hasher.update([0]);
+    if let Some(summary) = &entry.summary {
+        hasher.update(summary.content.as_bytes());
+    }
+    hasher.update([0]);
+    if let Some(body) = entry.content.as_ref().and_then(|x| x.body.as_ref()) {
+        hasher.update(body.as_bytes());
+    }
+    return format!("{:x}", hasher.finalize());
+}
+
+/**
+ * Escape the special HTML characters for markdown output, so they are shown
+ * as is. HTML output is already sanitized and plain text is shown as is.
+ */
+pub fn escape(input: String, format: Format) -> String {
+    if format == Format::Markdown {
+        return input
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;");
+    }
+    return input;
+}
+
+/**
+ * Find an image for an entry, this is the first media thumbnail, or else the
+ * first media content or enclosure that is an image.
+ */
+pub fn entry_image(entry: &model::Entry) -> Option<String> {
+    let thumbnail = entry.media.iter().flat_map(|x| x.thumbnails.iter()).next();
+    if let Some(thumbnail) = thumbnail {
+        return Some(thumbnail.image.uri.to_owned());
+    }
+    let content = entry.media.iter().flat_map(|x| x.content.iter()).find(|x| {
+        x.url.is_some() && x.content_type.as_ref().map(|x| x.type_()) == Some(mime::IMAGE)
+    });
+    if let Some(content) = content {
+        return content.url.as_ref().map(|x| x.to_string());
+    }
+    let enclosure = entry.links.iter().find(|x| {
+        x.rel.as_deref() == Some("enclosure")
+            && x.media_type
+                .as_deref()
+                .is_some_and(|x| x.starts_with("image/"))
+    });
+    return enclosure.map(|x| x.href.to_owned());
+}
+
+/**
+ * This will parse the template string and render it with the context of the
+ * rss feed/entry/item (see template_context), see the template module for the
+ * syntax. The result is not escaped yet, see escape.
+ */
+pub fn fill_template(template_str: &str, ctx: &Value, feed_conf: &FeedConf) -> String {
+    match template::parse(template_str) {
+        Ok(nodes) => {
+            let opts = RenderOptions {
+                missing: feed_conf.missing_value.to_owned(),
+                timezone: feed_conf.tz,
+                date_format: feed_conf.date_format.to_owned(),
+                now: Utc::now(),
+            };
+            return template::render(&nodes, ctx, &opts);
+        }
+        Err(e) => {
+            error!("Invalid template {:?} ({})", template_str, e);
+            return format!("Invalid template ({})", e);
         }
     }
-    return escape(filled_str).to_owned();
 }
 
 /**
@@ -218,6 +906,29 @@ pub async fn fetch_feed(
     last_fetch_time: DateTime<Utc>,
 ) -> Result<Option<model::Feed>, Box<dyn Error>> {
     info!("Fetching feed \"{}\"", &feed_conf.url);
+    let start = Instant::now();
+    let mut outcome = "error".to_owned();
+    let res = fetch_and_parse(feed_conf, last_fetch_time, &mut outcome).await;
+    let duration = start.elapsed();
+    metrics::fetch(feed_conf.id, &outcome, duration.as_secs_f64());
+    tracing::info!(
+        status = %outcome,
+        duration_ms = duration.as_millis() as u64,
+        "Fetched feed \"{}\"",
+        &feed_conf.url
+    );
+    return res;
+}
+
+/**
+ * The actual fetch for fetch_feed, sets `outcome` to the status code once we
+ * got a response.
+ */
+async fn fetch_and_parse(
+    feed_conf: &FeedConf,
+    last_fetch_time: DateTime<Utc>,
+    outcome: &mut String,
+) -> Result<Option<model::Feed>, Box<dyn Error>> {
     let client = reqwest::Client::new();
     let last_fetch_rfc2822 = last_fetch_time.to_rfc2822().replace("+0000", "GMT");
     debug!(
@@ -230,11 +941,250 @@ pub async fn fetch_feed(
         .send()
         .await?;
     if resp.status() == 304 {
+        *outcome = "304".to_owned();
         info!("No changes since last fetch at {}", &last_fetch_rfc2822);
         Ok(None)
     } else {
+        let status = resp.status().as_u16().to_string();
         let feed = parser::parse(&resp.bytes().await?[..])?;
+        *outcome = status;
         debug!("{:#?}", feed);
         Ok(Some(feed))
     }
 }
+
+/**
+ * Load a feed from an url or a local file, without any caching headers. Used
+ * when we want to look at a feed outside of the normal fetch loop.
+ */
+pub async fn load_feed(source: &str) -> Result<model::Feed, Box<dyn Error>> {
+    let bytes;
+    if source.starts_with("http://") || source.starts_with("https://") {
+        info!("Fetching feed \"{}\"", source);
+        let resp = reqwest::get(source).await?.error_for_status()?;
+        bytes = resp.bytes().await?.to_vec();
+    } else {
+        info!("Reading feed from file \"{}\"", source);
+        bytes = std::fs::read(source)?;
+    }
+    let feed = parser::parse(&bytes[..])?;
+    debug!("{:#?}", feed);
+    return Ok(feed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Render a field of the entry in the captured blog feed, with the given
+     * feed config.
+     */
+    fn blog_field(field: &str, feed_conf: &FeedConf) -> String {
+        let feed = parser::parse(&include_bytes!("../tests/fixtures/blog.xml")[..]).unwrap();
+        let ctx = template_context(&feed.entries[0], &feed, feed_conf);
+        return fill_template_field(field, &ctx);
+    }
+
+    fn text_options(format: Format) -> TextOptions {
+        return TextOptions {
+            format,
+            strip_images: false,
+            strip_iframes: true,
+            collapse_whitespace: false,
+            base: None,
+        };
+    }
+
+    fn conf(format: Format) -> FeedConf {
+        return FeedConf {
+            format,
+            ..Default::default()
+        };
+    }
+
+    #[test]
+    fn legacy_fields_are_valid() {
+        let fields = [
+            "id",
+            "title",
+            "updated",
+            "authors",
+            "description",
+            "links",
+            "categories",
+            "contributors",
+            "language",
+            "published",
+            "rights",
+            "entry.id",
+            "entry.title",
+            "entry.updated",
+            "entry.authors",
+            "entry.links",
+            "entry.summary",
+            "entry.categories",
+            "entry.contributors",
+            "entry.published",
+            "entry.source",
+            "entry.rights",
+        ];
+        for field in fields {
+            assert_eq!(validate_template(&format!("{{{{{}}}}}", field)), Ok(()));
+        }
+        assert_eq!(
+            validate_template("{{title}}: {{entry.title}}\n{{entry.links}}"),
+            Ok(())
+        );
+        assert_eq!(
+            validate_template("{{entry.titel}}"),
+            Err("Unknown field \"entry.titel\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn blog_markdown() {
+        assert_eq!(
+            blog_field("entry.content", &conf(Format::Markdown)),
+            "The numbers for the last quarter are in.\n\
+             \n\
+             ![Chart](https://blog.example.com/wp-content/uploads/2024/01/chart.png)\n\
+             \n\
+             Watch the recording:\n\
+             \n\
+             See the [previous quarter](https://blog.example.com/2023/10/previous-quarter/) \
+             for comparison."
+        );
+        assert_eq!(
+            blog_field("entry.summary", &conf(Format::Markdown)),
+            "The numbers for the last quarter are in.\n\
+             \n\
+             See the [previous quarter](https://blog.example.com/2023/10/previous-quarter/) \
+             for comparison."
+        );
+    }
+
+    #[test]
+    fn blog_html() {
+        assert_eq!(
+            blog_field("entry.content", &conf(Format::Html)),
+            "<p>The numbers for the last quarter are in.</p>\n\
+             \n\
+             <p><img src=\"https://blog.example.com/wp-content/uploads/2024/01/chart.png\" \
+             width=\"600\" height=\"400\" alt=\"Chart\"></p>\n\
+             \n\
+             <p>Watch the recording:</p>\n\
+             \n\
+             <p>See the <a href=\"https://blog.example.com/2023/10/previous-quarter/\" \
+             rel=\"noopener noreferrer\">previous quarter</a> for comparison.</p>\n\
+             <img>\n\
+             <img>"
+        );
+    }
+
+    #[test]
+    fn blog_plain() {
+        assert_eq!(
+            blog_field("entry.content", &conf(Format::Plain)),
+            "The numbers for the last quarter are in.\n\
+             \n\
+             Watch the recording:\n\
+             \n\
+             See the previous quarter for comparison."
+        );
+    }
+
+    #[test]
+    fn removes_tracking_pixels() {
+        for format in [Format::Markdown, Format::Html, Format::Plain] {
+            for field in ["entry.summary", "entry.content"] {
+                let text = blog_field(field, &conf(format));
+                assert!(!text.contains("track.example.net"), "{}", text);
+            }
+        }
+        let pixel = "<img src=\"a.png\" alt=\"a>b\" width=\"1\"><img alt=\"x\">b";
+        assert_eq!(
+            sanitize_html(pixel, &text_options(Format::Html)),
+            "<img><img alt=\"x\">b"
+        );
+        let markdown = sanitize_html(pixel, &text_options(Format::Markdown));
+        assert_eq!(html_to_markdown(&markdown), "b");
+    }
+
+    #[test]
+    fn strips_scripts_and_iframes() {
+        for format in [Format::Markdown, Format::Html, Format::Plain] {
+            let text = blog_field("entry.content", &conf(format));
+            assert!(!text.contains("trackView"), "{}", text);
+            assert!(!text.contains("onclick"), "{}", text);
+            assert!(!text.contains("video.example.org"), "{}", text);
+        }
+        let with_iframes = FeedConf {
+            format: Format::Html,
+            strip_iframes: false,
+            ..Default::default()
+        };
+        assert!(blog_field("entry.content", &with_iframes).contains(
+            "<iframe src=\"https://video.example.org/embed/5678\" width=\"560\" height=\"315\">\
+             </iframe>"
+        ));
+    }
+
+    #[test]
+    fn rewrites_relative_urls() {
+        let text = blog_field("entry.content", &conf(Format::Html));
+        assert!(text.contains("href=\"https://blog.example.com/2023/10/previous-quarter/\""));
+        assert!(
+            text.contains("src=\"https://blog.example.com/wp-content/uploads/2024/01/chart.png\"")
+        );
+        assert!(!text.contains("=\"/"));
+    }
+
+    #[test]
+    fn strips_images() {
+        for format in [Format::Markdown, Format::Html] {
+            let strip = FeedConf {
+                format,
+                strip_images: true,
+                ..Default::default()
+            };
+            let text = blog_field("entry.content", &strip);
+            assert!(!text.contains("chart.png"), "{}", text);
+            assert!(text.contains("previous quarter"), "{}", text);
+        }
+        // Without a size html2md turns the image into markdown, HTML output
+        // keeps it
+        let image = "<img src=\"a.png\" width=\"600\" height=\"400\" alt=\"a>b\">";
+        assert_eq!(
+            sanitize_html(image, &text_options(Format::Markdown)),
+            "<img src=\"a.png\" alt=\"a>b\">"
+        );
+        assert_eq!(
+            sanitize_html(image, &text_options(Format::Html)),
+            "<img src=\"a.png\" width=\"600\" height=\"400\" alt=\"a>b\">"
+        );
+    }
+
+    #[test]
+    fn collapses_whitespace() {
+        let text = "  indented   line  \n\n\n\nnext    line  \n   \n last";
+        assert_eq!(
+            collapse_whitespace(text, Format::Markdown),
+            "  indented line  \n\nnext line  \n\n last"
+        );
+        assert_eq!(
+            collapse_whitespace(text, Format::Html),
+            "  indented line\n\nnext line\n\n last"
+        );
+        assert_eq!(
+            collapse_whitespace(text, Format::Plain),
+            "  indented line\n\nnext line\n\n last"
+        );
+        let keep = FeedConf {
+            format: Format::Plain,
+            collapse_whitespace: false,
+            ..Default::default()
+        };
+        assert!(blog_field("entry.summary", &keep).contains("The   numbers   for"));
+    }
+}
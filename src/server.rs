@@ -0,0 +1,198 @@
+use crate::api;
+use crate::database;
+use crate::health;
+use crate::metrics;
+use crate::shutdown;
+use crate::web;
+
+use base64::Engine;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use log::{error, info, warn};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::env;
+use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task;
+
+/**
+ * What the request handlers share with the rest of the app.
+ */
+pub struct State {
+    /// The admin API and web UI are disabled without a token
+    pub admin_token: Option<String>,
+    /// Token the forms of the web UI send along, so other sites can not
+    /// submit them for a logged in user
+    pub csrf_token: String,
+    /// Wakes up the main loop to check the feeds right away
+    pub fetch_now: Arc<Notify>,
+}
+
+/**
+ * A json response with the given status.
+ */
+pub fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+}
+
+/**
+ * A json response with an error message.
+ */
+pub fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    return json_response(status, serde_json::json!({ "error": message }));
+}
+
+/**
+ * Compare two strings in a time that does not depend on where they differ,
+ * so the admin token can not be guessed one character at a time.
+ */
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    return a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+/**
+ * Check the `Authorization` header of a request. The API uses
+ * `Bearer <token>`, browsers use basic auth with the token as password and
+ * any user name.
+ */
+fn authorized(req: &Request<Body>, token: &str) -> bool {
+    let header_res = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|x| x.to_str().ok());
+    if let None = header_res {
+        return false;
+    }
+    let header = header_res.unwrap();
+    if let Some(x) = header.strip_prefix("Bearer ") {
+        return constant_time_eq(x.trim().as_bytes(), token.as_bytes());
+    }
+    if let Some(x) = header.strip_prefix("Basic ") {
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(x.trim())
+            .unwrap_or_default();
+        let password = decoded
+            .iter()
+            .position(|x| *x == b':')
+            .map(|i| &decoded[i + 1..])
+            .unwrap_or_default();
+        return constant_time_eq(password, token.as_bytes());
+    }
+    return false;
+}
+
+/**
+ * Route a request to the part of the app that handles it.
+ */
+async fn handle(req: Request<Body>, state: Arc<State>) -> Result<Response<Body>, Infallible> {
+    let path = req.uri().path();
+    // Prometheus scrapes without credentials, and the metrics hold no secrets
+    if path == "/metrics" {
+        let res = Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render()))
+            .unwrap();
+        return Ok(res);
+    }
+    // Probes for Kubernetes, also without credentials
+    if path == "/healthz" || path == "/readyz" {
+        let res = if path == "/healthz" {
+            Ok(health::check())
+        } else {
+            // Connecting to the database blocks
+            task::spawn_blocking(database::check_ready).await
+        };
+        match res.unwrap_or_else(|e| Err(e.to_string())) {
+            Ok(_) => return Ok(json_response(StatusCode::OK, json!({ "status": "ok" }))),
+            Err(e) => return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, &e)),
+        }
+    }
+    let is_api = path == "/api" || path.starts_with("/api/");
+    if let None = state.admin_token {
+        return Ok(error_response(
+            StatusCode::NOT_FOUND,
+            "The admin API and web UI are disabled, set $ADMIN_TOKEN to enable them",
+        ));
+    }
+    if !authorized(&req, state.admin_token.as_ref().unwrap()) {
+        let mut res = error_response(StatusCode::UNAUTHORIZED, "Unauthorized");
+        // Make browsers ask for the token
+        if !is_api {
+            res.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                header::HeaderValue::from_static("Basic realm=\"rss-watcher\""),
+            );
+        }
+        return Ok(res);
+    }
+    if is_api {
+        return Ok(api::handle(req, &state).await);
+    }
+    return Ok(web::handle(req, state).await);
+}
+
+/**
+ * Start the HTTP server in the background if `$HTTP_PORT` is set. It always
+ * serves the metrics, the admin API and web UI only when `$ADMIN_TOKEN` is
+ * set.
+ */
+pub fn start(fetch_now: Arc<Notify>) {
+    let port;
+    match env::var("HTTP_PORT") {
+        Ok(val) => match val.parse::<u16>() {
+            Ok(x) => port = x,
+            Err(_e) => {
+                error!("Invalid $HTTP_PORT value {:#?}", val);
+                process::exit(1);
+            }
+        },
+        Err(_e) => return,
+    }
+    let admin_token = env::var("ADMIN_TOKEN")
+        .ok()
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty());
+    if let None = admin_token {
+        warn!("$ADMIN_TOKEN not set, the admin API and web UI are disabled");
+    }
+
+    let csrf_token = format!(
+        "{:x}",
+        Sha256::digest(format!("csrf:{}", admin_token.as_deref().unwrap_or("")))
+    );
+    let state = Arc::new(State {
+        admin_token,
+        csrf_token,
+        fetch_now,
+    });
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone()))) }
+    });
+    let server;
+    match Server::try_bind(&addr) {
+        Ok(x) => server = x.serve(make_svc).with_graceful_shutdown(shutdown::wait()),
+        Err(e) => {
+            error!("Could not listen on {} ({})", addr, e);
+            process::exit(1);
+        }
+    }
+    info!("Listening on {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("HTTP server stopped ({})", e);
+        }
+    });
+}
@@ -0,0 +1,74 @@
+use log::{error, info, warn};
+use std::env;
+use std::process;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time;
+
+/**
+ * Set to true once we got a signal to shut down.
+ */
+static SHUTDOWN: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn sender() -> &'static watch::Sender<bool> {
+    return SHUTDOWN.get_or_init(|| watch::channel(false).0);
+}
+
+/**
+ * Wether we got a signal to shut down, no new work should be started then.
+ */
+pub fn requested() -> bool {
+    return *sender().borrow();
+}
+
+/**
+ * Wait until we get a signal to shut down.
+ */
+pub async fn wait() {
+    let mut rx = sender().subscribe();
+    let _ = rx.wait_for(|x| *x).await;
+}
+
+/**
+ * Listen for SIGTERM and SIGINT in the background. On the first signal the
+ * shutdown is requested, so the work in progress can be finished. If that
+ * takes longer than `$SHUTDOWN_TIMEOUT` seconds (defaults to 25), or we get a
+ * second signal, the process exits right away.
+ */
+pub fn listen() {
+    let timeout;
+    match env::var("SHUTDOWN_TIMEOUT") {
+        Ok(val) => match val.parse::<u64>() {
+            Ok(x) => timeout = x,
+            Err(_e) => {
+                error!("Invalid $SHUTDOWN_TIMEOUT value {:#?}", val);
+                process::exit(1);
+            }
+        },
+        Err(_e) => timeout = 25,
+    }
+    // Install the handlers right away, so no signal is missed
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
+        info!(
+            "Shutting down, finishing the work in progress (at most {}s)",
+            timeout
+        );
+        sender().send_replace(true);
+        tokio::select! {
+            _ = time::sleep(Duration::from_secs(timeout)) => {
+                warn!("Work in progress did not finish in {}s, exiting anyway", timeout);
+            }
+            _ = sigterm.recv() => warn!("Got a second signal, exiting right away"),
+            _ = sigint.recv() => warn!("Got a second signal, exiting right away"),
+        }
+        process::exit(1);
+    });
+}
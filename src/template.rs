@@ -0,0 +1,1054 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::prelude::{DateTime, Utc};
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+
+/**
+ * A value that can be used in a template. Lists and objects carry the string
+ * they are displayed as when used directly in `{{ }}`, so `{{entry.links}}`
+ * renders the same as it always has, while still being possible to loop over.
+ * `None` is a field that is known, but was not in the feed, it is rendered as
+ * the configured default for missing fields.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Date(DateTime<Utc>),
+    List(Vec<Value>, String),
+    Object(BTreeMap<String, Value>, String),
+    None,
+}
+
+impl Value {
+    /**
+     * The string this value is rendered as.
+     */
+    pub fn display(&self) -> String {
+        match self {
+            Value::Text(x) => return x.to_owned(),
+            Value::Date(x) => return x.to_rfc2822().replace("+0000", "UTC"),
+            Value::List(_, x) => return x.to_owned(),
+            Value::Object(_, x) => return x.to_owned(),
+            Value::None => return "".to_owned(),
+        }
+    }
+
+    /**
+     * Wether this value counts as true in an `{% if %}`.
+     */
+    fn is_truthy(&self) -> bool {
+        match self {
+            Value::Text(x) => return !x.is_empty(),
+            Value::Date(_) => return true,
+            Value::List(x, _) => return !x.is_empty(),
+            Value::Object(_, _) => return true,
+            Value::None => return false,
+        }
+    }
+
+    /**
+     * Get a field of an object, or an item of a list by its index. Getting a
+     * field of a list gets that field of the first item, so
+     * `entry.media.thumbnail` is the same as `entry.media.0.thumbnail`.
+     */
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields, _) => return fields.get(key),
+            Value::List(items, _) => match key.parse::<usize>() {
+                Ok(i) => return items.get(i),
+                Err(_) => return items.first()?.get(key),
+            },
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Arg {
+    Str(String),
+    Int(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    name: String,
+    args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    path: String,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    negate: bool,
+    expr: Expr,
+}
+
+/**
+ * A parsed template is a list of nodes.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Text(String),
+    Output(Expr),
+    If(Vec<(Condition, Vec<Node>)>, Vec<Node>),
+    For(String, Expr, Vec<Node>),
+}
+
+enum Piece {
+    Text(String),
+    Output(String),
+    Tag(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Int(usize),
+    Pipe,
+    LParen,
+    RParen,
+    Comma,
+}
+
+/**
+ * The filters we know, and the number of arguments they take.
+ */
+const FILTERS: [(&str, usize); 9] = [
+    ("truncate", 1),
+    ("lower", 0),
+    ("upper", 0),
+    ("trim", 0),
+    ("strip_html", 0),
+    ("default", 1),
+    ("date", 1),
+    ("relative", 0),
+    ("join", 1),
+];
+
+/**
+ * Options for how values are rendered.
+ */
+pub struct RenderOptions {
+    /// What to render fields that are not in the feed as
+    pub missing: String,
+    /// The timezone dates are shown in
+    pub timezone: Tz,
+    /// strftime format for dates, or `relative`. RFC 2822 if None
+    pub date_format: Option<String>,
+    /// The time relative dates are relative to
+    pub now: DateTime<Utc>,
+}
+
+/**
+ * Check that a date format is either a valid strftime format, or `relative`.
+ */
+pub fn validate_date_format(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|x| x == Item::Error) {
+        return Err(format!("Invalid date format {:?}", format));
+    }
+    return Ok(());
+}
+
+/**
+ * Describe how long ago (or how far into the future) a date is, e.g.
+ * "5 minutes ago".
+ */
+fn relative_date(date: &DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (now - *date).num_seconds();
+    if secs.abs() < 60 {
+        return "just now".to_owned();
+    }
+    let units = [
+        (365 * 24 * 3600, "year"),
+        (30 * 24 * 3600, "month"),
+        (24 * 3600, "day"),
+        (3600, "hour"),
+        (60, "minute"),
+    ];
+    let (size, unit) = units.iter().find(|(size, _)| secs.abs() >= *size).unwrap();
+    let n = secs.abs() / size;
+    let plural = if n == 1 { "" } else { "s" };
+    if secs < 0 {
+        return format!("in {} {}{}", n, unit, plural);
+    }
+    return format!("{} {}{} ago", n, unit, plural);
+}
+
+/**
+ * Format a date in the configured timezone with the given format, RFC 2822 if
+ * there is no format.
+ */
+fn format_date(date: &DateTime<Utc>, format: Option<&str>, opts: &RenderOptions) -> String {
+    let local = date.with_timezone(&opts.timezone);
+    match format {
+        Some("relative") => return relative_date(date, opts.now),
+        Some(fmt) => return local.format(fmt).to_string(),
+        None => return local.to_rfc2822().replace("+0000", "UTC"),
+    }
+}
+
+/**
+ * Split the template into text, `{{ }}` and `{% %}` pieces.
+ */
+fn split(template: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    loop {
+        let start = match (rest.find("{{"), rest.find("{%")) {
+            (Some(a), Some(b)) => a.min(b),
+            (Some(a), None) => a,
+            (None, Some(b)) => b,
+            (None, None) => break,
+        };
+        if start > 0 {
+            pieces.push(Piece::Text(rest[..start].to_owned()));
+        }
+        let is_tag = rest[start..].starts_with("{%");
+        let close = if is_tag { "%}" } else { "}}" };
+        let end = match rest[start + 2..].find(close) {
+            Some(x) => start + 2 + x,
+            None => {
+                return Err(format!(
+                    "Unclosed {:?} in template",
+                    &rest[start..start + 2]
+                ))
+            }
+        };
+        let inner = rest[start + 2..end].trim().to_owned();
+        if is_tag {
+            pieces.push(Piece::Tag(inner));
+        } else {
+            pieces.push(Piece::Output(inner));
+        }
+        rest = &rest[end + 2..];
+    }
+    if !rest.is_empty() {
+        pieces.push(Piece::Text(rest.to_owned()));
+    }
+    return Ok(pieces);
+}
+
+/**
+ * Split an expression like `entry.summary | truncate(200)` into tokens.
+ */
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            ' ' | '\t' | '\n' | '\r' => i += 1,
+            '|' => {
+                tokens.push(Token::Pipe);
+                i += 1;
+            }
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '"' | '\'' => {
+                let mut s = "".to_owned();
+                i += 1;
+                while i < chars.len() && chars[i] != c {
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(format!("Unclosed string in {:?}", input));
+                }
+                tokens.push(Token::Str(s));
+                i += 1;
+            }
+            '0'..='9' => {
+                let mut s = "".to_owned();
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    s.push(chars[i]);
+                    i += 1;
+                }
+                match s.parse() {
+                    Ok(x) => tokens.push(Token::Int(x)),
+                    Err(_e) => return Err(format!("Invalid number {:?}", s)),
+                }
+            }
+            _ if c.is_alphanumeric() || c == '_' => {
+                // Fields are written with dots, but we allow indexing lists
+                // with brackets too, `entry.links[0].href` is the same as
+                // `entry.links.0.href`
+                let mut s = "".to_owned();
+                while i < chars.len() && (chars[i].is_alphanumeric() || "_.[]".contains(chars[i])) {
+                    match chars[i] {
+                        '[' => s.push('.'),
+                        ']' => {}
+                        x => s.push(x),
+                    }
+                    i += 1;
+                }
+                tokens.push(Token::Ident(s));
+            }
+            _ => return Err(format!("Unexpected {:?} in {:?}", c, input)),
+        }
+    }
+    return Ok(tokens);
+}
+
+/**
+ * Parse an expression, that is a field path followed by any number of
+ * filters.
+ */
+fn parse_expr(input: &str) -> Result<Expr, String> {
+    let tokens = tokenize(input)?;
+    let mut iter = tokens.into_iter().peekable();
+
+    let path = match iter.next() {
+        Some(Token::Ident(x)) => x,
+        _ => return Err(format!("Expected a field in {:?}", input)),
+    };
+    if path.split('.').any(|x| x.is_empty()) {
+        return Err(format!("Invalid field {:?}", path));
+    }
+
+    let mut filters = Vec::new();
+    while let Some(token) = iter.next() {
+        if token != Token::Pipe {
+            return Err(format!("Expected \"|\" in {:?}", input));
+        }
+        let name = match iter.next() {
+            Some(Token::Ident(x)) => x,
+            _ => return Err(format!("Expected a filter name in {:?}", input)),
+        };
+        let mut args = Vec::new();
+        if iter.peek() == Some(&Token::LParen) {
+            iter.next();
+            loop {
+                match iter.next() {
+                    Some(Token::Str(x)) => args.push(Arg::Str(x)),
+                    Some(Token::Int(x)) => args.push(Arg::Int(x)),
+                    Some(Token::RParen) if args.is_empty() => break,
+                    _ => return Err(format!("Invalid arguments to filter {:?}", name)),
+                }
+                match iter.next() {
+                    Some(Token::Comma) => continue,
+                    Some(Token::RParen) => break,
+                    _ => return Err(format!("Invalid arguments to filter {:?}", name)),
+                }
+            }
+        }
+        validate_filter(&name, &args)?;
+        filters.push(Filter { name, args });
+    }
+    return Ok(Expr { path, filters });
+}
+
+/**
+ * Make sure the filter exists and got the right arguments.
+ */
+fn validate_filter(name: &str, args: &[Arg]) -> Result<(), String> {
+    let arity = match FILTERS.iter().find(|(x, _)| *x == name) {
+        Some((_, n)) => *n,
+        None => return Err(format!("Unknown filter {:?}", name)),
+    };
+    if args.len() != arity {
+        return Err(format!(
+            "Filter {:?} takes {} argument(s), got {}",
+            name,
+            arity,
+            args.len()
+        ));
+    }
+    match (name, args.first()) {
+        ("truncate", Some(Arg::Int(_))) => {}
+        ("truncate", _) => return Err("Filter \"truncate\" takes a number".to_owned()),
+        ("date", Some(Arg::Str(fmt))) => validate_date_format(fmt)?,
+        ("default" | "date" | "join", Some(Arg::Int(x))) => {
+            return Err(format!("Filter {:?} takes a string, got {}", name, x))
+        }
+        _ => {}
+    }
+    return Ok(());
+}
+
+/**
+ * Parse an `{% if %}`/`{% elif %}` condition, which is an expression that may
+ * be prefixed with `not`.
+ */
+fn parse_condition(input: &str) -> Result<Condition, String> {
+    if let Some(rest) = input.strip_prefix("not ") {
+        return Ok(Condition {
+            negate: true,
+            expr: parse_expr(rest)?,
+        });
+    }
+    return Ok(Condition {
+        negate: false,
+        expr: parse_expr(input)?,
+    });
+}
+
+/**
+ * Parse pieces into nodes until we hit one of the tags in `until`, returns the
+ * nodes and the tag we stopped at.
+ */
+fn parse_nodes(
+    pieces: &[Piece],
+    pos: &mut usize,
+    until: &[&str],
+) -> Result<(Vec<Node>, Option<String>), String> {
+    let mut nodes = Vec::new();
+    while *pos < pieces.len() {
+        let piece = &pieces[*pos];
+        *pos += 1;
+        match piece {
+            Piece::Text(x) => nodes.push(Node::Text(x.to_owned())),
+            Piece::Output(x) => nodes.push(Node::Output(parse_expr(x)?)),
+            Piece::Tag(tag) => {
+                let keyword = tag.split_whitespace().next().unwrap_or("");
+                if until.contains(&keyword) {
+                    return Ok((nodes, Some(tag.to_owned())));
+                }
+                match keyword {
+                    "if" => nodes.push(parse_if(pieces, pos, &tag[2..])?),
+                    "for" => nodes.push(parse_for(pieces, pos, &tag[3..])?),
+                    _ => return Err(format!("Unexpected tag {{% {} %}}", tag)),
+                }
+            }
+        }
+    }
+    if !until.is_empty() {
+        return Err(format!("Missing {{% {} %}}", until[until.len() - 1]));
+    }
+    return Ok((nodes, None));
+}
+
+fn parse_if(pieces: &[Piece], pos: &mut usize, condition: &str) -> Result<Node, String> {
+    let mut branches = Vec::new();
+    let mut condition = parse_condition(condition.trim())?;
+    loop {
+        let (body, end) = parse_nodes(pieces, pos, &["elif", "else", "endif"])?;
+        branches.push((condition, body));
+        let end = end.unwrap();
+        if let Some(rest) = end.strip_prefix("elif") {
+            condition = parse_condition(rest.trim())?;
+            continue;
+        }
+        if end == "else" {
+            let (else_body, _) = parse_nodes(pieces, pos, &["endif"])?;
+            return Ok(Node::If(branches, else_body));
+        }
+        return Ok(Node::If(branches, Vec::new()));
+    }
+}
+
+fn parse_for(pieces: &[Piece], pos: &mut usize, head: &str) -> Result<Node, String> {
+    let parts: Vec<&str> = head.trim().splitn(3, ' ').collect();
+    if parts.len() != 3 || parts[1] != "in" || parts[0].contains('.') {
+        return Err(format!("Invalid for loop {{% for {} %}}", head.trim()));
+    }
+    let expr = parse_expr(parts[2])?;
+    let (body, _) = parse_nodes(pieces, pos, &["endfor"])?;
+    return Ok(Node::For(parts[0].to_owned(), expr, body));
+}
+
+/**
+ * Parse a template string. Returns a description of the problem if the
+ * template is not valid.
+ */
+pub fn parse(template: &str) -> Result<Vec<Node>, String> {
+    let pieces = split(template)?;
+    let mut pos = 0;
+    let (nodes, _) = parse_nodes(&pieces, &mut pos, &[])?;
+    return Ok(nodes);
+}
+
+/**
+ * Strip all tags from HTML, block level elements become line breaks, scripts
+ * and styles are dropped and the common entities are decoded.
+ */
+pub fn strip_html(content: &str) -> String {
+    const BLOCKS: [&str; 14] = [
+        "br",
+        "p",
+        "div",
+        "li",
+        "tr",
+        "h1",
+        "h2",
+        "h3",
+        "h4",
+        "h5",
+        "h6",
+        "blockquote",
+        "pre",
+        "hr",
+    ];
+    let mut out = "".to_owned();
+    let mut tag: Option<String> = None;
+    let mut skipping = false;
+    for c in content.chars() {
+        match (&mut tag, c) {
+            (None, '<') => tag = Some("".to_owned()),
+            (Some(raw), '>') => {
+                let closing = raw.starts_with('/');
+                let name = raw
+                    .trim_start_matches('/')
+                    .split(|x: char| x.is_whitespace() || x == '/')
+                    .next()
+                    .unwrap_or("")
+                    .to_lowercase();
+                if name == "script" || name == "style" {
+                    skipping = !closing;
+                } else if BLOCKS.contains(&name.as_str()) && !out.is_empty() && !out.ends_with('\n')
+                {
+                    out.push('\n');
+                }
+                tag = None;
+            }
+            (Some(raw), _) => raw.push(c),
+            (None, _) if !skipping => out.push(c),
+            (None, _) => {}
+        }
+    }
+    return out
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+        .trim()
+        .to_owned();
+}
+
+fn apply_filter(value: Value, filter: &Filter, opts: &RenderOptions) -> Value {
+    let arg_str = match filter.args.first() {
+        Some(Arg::Str(x)) => x.to_owned(),
+        _ => "".to_owned(),
+    };
+    match filter.name.as_str() {
+        "default" => {
+            if value.is_truthy() {
+                return value;
+            }
+            return Value::Text(arg_str);
+        }
+        "date" => {
+            if let Value::Date(x) = value {
+                return Value::Text(format_date(&x, Some(&arg_str), opts));
+            }
+            return value;
+        }
+        "relative" => {
+            if let Value::Date(x) = value {
+                return Value::Text(relative_date(&x, opts.now));
+            }
+            return value;
+        }
+        "join" => {
+            if let Value::List(items, _) = &value {
+                let strs: Vec<String> = items.iter().map(|x| x.display()).collect();
+                return Value::Text(strs.join(&arg_str));
+            }
+            return value;
+        }
+        _ => {}
+    }
+
+    // The rest are string filters, they leave missing fields alone
+    if let Value::None = value {
+        return value;
+    }
+    let s = value.display();
+    match filter.name.as_str() {
+        "truncate" => {
+            let n = match filter.args.first() {
+                Some(Arg::Int(x)) => *x,
+                _ => 0,
+            };
+            if s.chars().count() <= n {
+                return Value::Text(s);
+            }
+            let mut truncated: String = s.chars().take(n).collect();
+            truncated.push('…');
+            return Value::Text(truncated);
+        }
+        "lower" => return Value::Text(s.to_lowercase()),
+        "upper" => return Value::Text(s.to_uppercase()),
+        "trim" => return Value::Text(s.trim().to_owned()),
+        "strip_html" => return Value::Text(strip_html(&s)),
+        _ => return Value::Text(s),
+    }
+}
+
+/**
+ * Look up a dotted path like `entry.title` in the context.
+ */
+pub fn lookup(ctx: &Value, path: &str) -> Option<Value> {
+    let mut value = ctx;
+    for key in path.split('.') {
+        value = value.get(key)?;
+    }
+    return Some(value.to_owned());
+}
+
+struct Scope<'a> {
+    root: &'a Value,
+    opts: &'a RenderOptions,
+    vars: Vec<(String, Value)>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, path: &str) -> Option<Value> {
+        let (first, rest) = match path.split_once('.') {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (path, None),
+        };
+        for (name, value) in self.vars.iter().rev() {
+            if name == first {
+                return match rest {
+                    Some(rest) => lookup(value, rest),
+                    None => Some(value.to_owned()),
+                };
+            }
+        }
+        return lookup(self.root, path);
+    }
+
+    fn eval(&self, expr: &Expr) -> Value {
+        let mut value = match self.lookup(&expr.path) {
+            Some(x) => x,
+            None => Value::None,
+        };
+        for filter in &expr.filters {
+            value = apply_filter(value, filter, self.opts);
+        }
+        return value;
+    }
+
+    fn render(&mut self, nodes: &[Node], out: &mut String) {
+        for node in nodes {
+            match node {
+                Node::Text(x) => out.push_str(x),
+                Node::Output(expr) => match self.eval(expr) {
+                    Value::None => out.push_str(&self.opts.missing),
+                    Value::Date(x) => {
+                        let format = self.opts.date_format.as_deref();
+                        out.push_str(&format_date(&x, format, self.opts))
+                    }
+                    x => out.push_str(&x.display()),
+                },
+                Node::If(branches, else_body) => {
+                    let branch = branches
+                        .iter()
+                        .find(|(cond, _)| self.eval(&cond.expr).is_truthy() != cond.negate);
+                    match branch {
+                        Some((_, body)) => self.render(body, out),
+                        None => self.render(else_body, out),
+                    }
+                }
+                Node::For(name, expr, body) => {
+                    let items = match self.eval(expr) {
+                        Value::List(items, _) => items,
+                        _ => continue,
+                    };
+                    for (i, item) in items.iter().enumerate() {
+                        let mut loop_fields = BTreeMap::new();
+                        loop_fields.insert("index".to_owned(), Value::Text((i + 1).to_string()));
+                        loop_fields.insert("first".to_owned(), bool_value(i == 0));
+                        loop_fields.insert("last".to_owned(), bool_value(i == items.len() - 1));
+                        self.vars
+                            .push(("loop".to_owned(), Value::Object(loop_fields, "".to_owned())));
+                        self.vars.push((name.to_owned(), item.to_owned()));
+                        self.render(body, out);
+                        self.vars.pop();
+                        self.vars.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn bool_value(b: bool) -> Value {
+    if b {
+        return Value::Text("true".to_owned());
+    }
+    return Value::None;
+}
+
+/**
+ * Render a parsed template with the given context.
+ */
+pub fn render(nodes: &[Node], ctx: &Value, opts: &RenderOptions) -> String {
+    let mut out = "".to_owned();
+    let mut scope = Scope {
+        root: ctx,
+        opts,
+        vars: Vec::new(),
+    };
+    scope.render(nodes, &mut out);
+    return out;
+}
+
+/**
+ * Look up a path in the schema, a context where every list has exactly one
+ * example item. Any index into a list resolves to that item.
+ */
+fn lookup_schema(schema: &Value, path: &str) -> Option<Value> {
+    let mut value = schema;
+    for key in path.split('.') {
+        value = match value {
+            Value::List(items, _) if key.parse::<usize>().is_ok() => items.first()?,
+            _ => value.get(key)?,
+        };
+    }
+    return Some(value.to_owned());
+}
+
+fn validate_nodes(
+    nodes: &[Node],
+    schema: &Value,
+    vars: &mut Vec<(String, Value)>,
+) -> Result<(), String> {
+    let check = |expr: &Expr, vars: &Vec<(String, Value)>| -> Result<Value, String> {
+        let (first, rest) = match expr.path.split_once('.') {
+            Some((first, rest)) => (first, Some(rest)),
+            None => (expr.path.as_str(), None),
+        };
+        let var = vars.iter().rev().find(|(name, _)| name == first);
+        let res = match (var, rest) {
+            (Some((_, value)), Some(rest)) => lookup_schema(value, rest),
+            (Some((_, value)), None) => Some(value.to_owned()),
+            (None, _) => lookup_schema(schema, &expr.path),
+        };
+        match res {
+            Some(x) => return Ok(x),
+            None => return Err(format!("Unknown field {:?}", expr.path)),
+        }
+    };
+    for node in nodes {
+        match node {
+            Node::Text(_) => {}
+            Node::Output(expr) => {
+                check(expr, vars)?;
+            }
+            Node::If(branches, else_body) => {
+                for (cond, body) in branches {
+                    check(&cond.expr, vars)?;
+                    validate_nodes(body, schema, vars)?;
+                }
+                validate_nodes(else_body, schema, vars)?;
+            }
+            Node::For(name, expr, body) => {
+                let item = match check(expr, vars)? {
+                    Value::List(items, _) => items.first().cloned().unwrap_or(Value::None),
+                    _ => return Err(format!("Field {:?} is not a list", expr.path)),
+                };
+                let mut loop_fields = BTreeMap::new();
+                loop_fields.insert("index".to_owned(), Value::None);
+                loop_fields.insert("first".to_owned(), Value::None);
+                loop_fields.insert("last".to_owned(), Value::None);
+                vars.push(("loop".to_owned(), Value::Object(loop_fields, "".to_owned())));
+                vars.push((name.to_owned(), item));
+                let res = validate_nodes(body, schema, vars);
+                vars.pop();
+                vars.pop();
+                res?;
+            }
+        }
+    }
+    return Ok(());
+}
+
+/**
+ * Make sure all fields used in the template exist in the schema, see
+ * lookup_schema.
+ */
+pub fn validate(nodes: &[Node], schema: &Value) -> Result<(), String> {
+    return validate_nodes(nodes, schema, &mut Vec::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn text(x: &str) -> Value {
+        return Value::Text(x.to_owned());
+    }
+
+    fn object(fields: Vec<(&str, Value)>, display: &str) -> Value {
+        let map = fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect();
+        return Value::Object(map, display.to_owned());
+    }
+
+    fn date() -> DateTime<Utc> {
+        return Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap();
+    }
+
+    fn context() -> Value {
+        let links = vec![
+            object(
+                vec![("href", text("https://a.example"))],
+                "[a](https://a.example)",
+            ),
+            object(
+                vec![("href", text("https://b.example"))],
+                "[b](https://b.example)",
+            ),
+        ];
+        let entry = object(
+            vec![
+                ("title", text("Hello <b>World</b> &amp; more")),
+                ("summary", text("  A summary  ")),
+                ("published", Value::Date(date())),
+                ("updated", Value::None),
+                (
+                    "links",
+                    Value::List(
+                        links,
+                        "[a](https://a.example), [b](https://b.example)".to_owned(),
+                    ),
+                ),
+                (
+                    "categories",
+                    Value::List(vec![text("rust"), text("rss")], "rust, rss".to_owned()),
+                ),
+                ("authors", Value::List(Vec::new(), "".to_owned())),
+            ],
+            "",
+        );
+        return object(
+            vec![
+                ("title", text("My feed")),
+                ("description", Value::None),
+                ("entry", entry),
+            ],
+            "",
+        );
+    }
+
+    fn opts() -> RenderOptions {
+        return RenderOptions {
+            missing: "?".to_owned(),
+            timezone: Tz::Europe__Amsterdam,
+            date_format: None,
+            now: date() + chrono::Duration::minutes(5),
+        };
+    }
+
+    fn render_str(template: &str) -> String {
+        let nodes = parse(template).unwrap();
+        validate(&nodes, &context()).unwrap();
+        return render(&nodes, &context(), &opts());
+    }
+
+    fn parse_err(template: &str) -> String {
+        return parse(template).unwrap_err();
+    }
+
+    #[test]
+    fn legacy_placeholders() {
+        assert_eq!(
+            render_str("{{title}}: {{entry.title}}"),
+            "My feed: Hello <b>World</b> &amp; more"
+        );
+        assert_eq!(
+            render_str("{{entry.links}}"),
+            "[a](https://a.example), [b](https://b.example)"
+        );
+        // Dates render the way they always have in UTC
+        let nodes = parse("{{entry.published}}").unwrap();
+        let utc = RenderOptions {
+            timezone: Tz::UTC,
+            ..opts()
+        };
+        assert_eq!(
+            render(&nodes, &context(), &utc),
+            "Tue, 02 Jan 2024 03:04:05 UTC"
+        );
+        assert_eq!(
+            render_str("{{entry.published}}"),
+            "Tue, 02 Jan 2024 04:04:05 +0100"
+        );
+        assert_eq!(render_str("Plain text only"), "Plain text only");
+        assert_eq!(render_str("{{ title }}"), "My feed");
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert_eq!(render_str("[{{description}}]"), "[?]");
+        assert_eq!(render_str("[{{entry.updated}}]"), "[?]");
+    }
+
+    #[test]
+    fn conditionals() {
+        let template = "{% if entry.updated %}u{% elif not description %}d{% else %}e{% endif %}";
+        assert_eq!(render_str(template), "d");
+        assert_eq!(render_str("{% if title %}t{% else %}e{% endif %}"), "t");
+        assert_eq!(render_str("{% if not title %}t{% else %}e{% endif %}"), "e");
+        assert_eq!(render_str("{% if entry.authors %}a{% endif %}"), "");
+        assert_eq!(
+            render_str("{% if entry.updated %}u{% elif entry.authors %}a{% else %}e{% endif %}"),
+            "e"
+        );
+    }
+
+    #[test]
+    fn loops() {
+        let template = "{% for link in entry.links %}\
+                        {% if loop.first %}[{% endif %}\
+                        {{ loop.index }}={{ link.href }}\
+                        {% if loop.last %}]{% else %}, {% endif %}\
+                        {% endfor %}";
+        assert_eq!(
+            render_str(template),
+            "[1=https://a.example, 2=https://b.example]"
+        );
+        assert_eq!(render_str("{{ entry.links[1].href }}"), "https://b.example");
+        assert_eq!(render_str("{{ entry.links.0.href }}"), "https://a.example");
+        assert_eq!(render_str("{% for a in entry.authors %}x{% endfor %}"), "");
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(render_str("{{ title | truncate(2) }}"), "My…");
+        assert_eq!(render_str("{{ title | truncate(7) }}"), "My feed");
+        assert_eq!(render_str("{{ title | lower }}"), "my feed");
+        assert_eq!(render_str("{{ title | upper }}"), "MY FEED");
+        assert_eq!(render_str("[{{ entry.summary | trim }}]"), "[A summary]");
+        assert_eq!(
+            render_str("{{ entry.title | strip_html }}"),
+            "Hello World & more"
+        );
+        assert_eq!(render_str("{{ description | default(\"none\") }}"), "none");
+        assert_eq!(render_str("{{ title | default('none') }}"), "My feed");
+        assert_eq!(
+            render_str("{{ entry.published | date(\"%H:%M\") }}"),
+            "04:04"
+        );
+        assert_eq!(
+            render_str("{{ entry.published | relative }}"),
+            "5 minutes ago"
+        );
+        assert_eq!(
+            render_str("{{ entry.categories | join(\" / \") }}"),
+            "rust / rss"
+        );
+        assert_eq!(
+            render_str("{{ entry.summary | trim | upper | truncate(1) }}"),
+            "A…"
+        );
+        // String filters leave missing fields alone
+        assert_eq!(render_str("{{ description | upper }}"), "?");
+    }
+
+    #[test]
+    fn bad_filter_arguments() {
+        assert_eq!(
+            parse_err("{{ title | truncate(\"x\") }}"),
+            "Filter \"truncate\" takes a number"
+        );
+        assert_eq!(
+            parse_err("{{ title | truncate }}"),
+            "Filter \"truncate\" takes 1 argument(s), got 0"
+        );
+        assert_eq!(
+            parse_err("{{ title | upper(1) }}"),
+            "Filter \"upper\" takes 0 argument(s), got 1"
+        );
+        assert_eq!(
+            parse_err("{{ title | default(3) }}"),
+            "Filter \"default\" takes a string, got 3"
+        );
+        assert_eq!(
+            parse_err("{{ entry.published | date(\"%Q\") }}"),
+            "Invalid date format \"%Q\""
+        );
+        assert_eq!(
+            parse_err("{{ title | truncate(1, 2 }}"),
+            "Invalid arguments to filter \"truncate\""
+        );
+        assert_eq!(
+            parse_err("{{ title | truncate(99999999999999999999999) }}"),
+            "Invalid number \"99999999999999999999999\""
+        );
+        assert_eq!(parse_err("{{ title | shout }}"), "Unknown filter \"shout\"");
+    }
+
+    #[test]
+    fn unknown_fields() {
+        let ctx = context();
+        let check = |x: &str| validate(&parse(x).unwrap(), &ctx);
+        assert_eq!(
+            check("{{ entry.titel }}"),
+            Err("Unknown field \"entry.titel\"".to_owned())
+        );
+        assert_eq!(
+            check("{% if entry.nope %}{% endif %}"),
+            Err("Unknown field \"entry.nope\"".to_owned())
+        );
+        assert_eq!(
+            check("{% for x in entry.links %}{{ x.nope }}{% endfor %}"),
+            Err("Unknown field \"x.nope\"".to_owned())
+        );
+        assert_eq!(
+            check("{% for x in title %}{% endfor %}"),
+            Err("Field \"title\" is not a list".to_owned())
+        );
+        assert_eq!(
+            check("{{ loop.index }}"),
+            Err("Unknown field \"loop.index\"".to_owned())
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert_eq!(parse_err("{{ title"), "Unclosed \"{{\" in template");
+        assert_eq!(parse_err("{% if title }}"), "Unclosed \"{%\" in template");
+        assert_eq!(parse_err("{% if title %}x"), "Missing {% endif %}");
+        assert_eq!(
+            parse_err("{% for x in entry.links %}"),
+            "Missing {% endfor %}"
+        );
+        assert_eq!(parse_err("{% endif %}"), "Unexpected tag {% endif %}");
+        assert_eq!(parse_err("{% while x %}"), "Unexpected tag {% while x %}");
+        assert_eq!(
+            parse_err("{% for x of entry.links %}{% endfor %}"),
+            "Invalid for loop {% for x of entry.links %}"
+        );
+        assert_eq!(parse_err("{{ }}"), "Expected a field in \"\"");
+        assert_eq!(
+            parse_err("{{ entry..title }}"),
+            "Invalid field \"entry..title\""
+        );
+        assert_eq!(
+            parse_err("{{ title upper }}"),
+            "Expected \"|\" in \"title upper\""
+        );
+        assert_eq!(
+            parse_err("{{ title | }}"),
+            "Expected a filter name in \"title |\""
+        );
+        assert_eq!(
+            parse_err("{{ title | default(\"x) }}"),
+            "Unclosed string in \"title | default(\\\"x)\""
+        );
+        assert_eq!(
+            parse_err("{{ title + 1 }}"),
+            "Unexpected '+' in \"title + 1\""
+        );
+    }
+}
@@ -0,0 +1,379 @@
+use crate::database::Format;
+
+/**
+ * HTML elements that have no closing tag.
+ */
+const VOID_ELEMENTS: [&str; 8] = ["br", "hr", "img", "input", "meta", "link", "wbr", "source"];
+
+/**
+ * A piece of text that must not be cut in half, like a markdown link, an HTML
+ * tag or an entity. Plain characters are atoms of their own.
+ */
+struct Atom {
+    start: usize,
+    end: usize,
+    chars: usize,
+    tag: Option<Tag>,
+}
+
+/**
+ * An HTML tag that opens or closes an element.
+ */
+enum Tag {
+    Open(String),
+    Close(String),
+}
+
+/**
+ * Find the end (exclusive) of the bracketed span starting at `start`, taking
+ * nested brackets into account.
+ */
+fn matching(text: &str, start: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text[start..].char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(start + i + c.len_utf8());
+            }
+        }
+    }
+    return None;
+}
+
+/**
+ * The end of a markdown link or image (`[text](url)` / `![alt](url)`)
+ * starting at `start`, if there is one.
+ */
+fn markdown_link(text: &str, start: usize) -> Option<usize> {
+    let bracket = if text[start..].starts_with("![") {
+        start + 1
+    } else {
+        start
+    };
+    let text_end = matching(text, bracket, '[', ']')?;
+    if !text[text_end..].starts_with('(') {
+        return None;
+    }
+    return matching(text, text_end, '(', ')');
+}
+
+/**
+ * The end of an entity like `&amp;` or `&#39;` starting at `start`, if there
+ * is one.
+ */
+fn entity(text: &str, start: usize) -> Option<usize> {
+    let rest = &text[start + 1..];
+    let end = rest.find(';')?;
+    if end == 0 || end > 10 || !rest[..end].chars().all(|x| x.is_alphanumeric() || x == '#') {
+        return None;
+    }
+    return Some(start + end + 2);
+}
+
+/**
+ * The end of an HTML tag starting at `start` and the tag itself, if there is
+ * one. Comments and self closing tags are returned without a tag.
+ */
+fn html_tag(text: &str, start: usize) -> Option<(usize, Option<Tag>)> {
+    let end = start + text[start..].find('>')? + 1;
+    let inner = &text[start + 1..end - 1];
+    let name = inner
+        .trim_start_matches('/')
+        .split(|x: char| x.is_whitespace() || x == '/')
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    if name.is_empty() || !name.chars().all(|x| x.is_ascii_alphanumeric()) {
+        if inner.starts_with('!') {
+            return Some((end, None));
+        }
+        return None;
+    }
+    if inner.starts_with('/') {
+        return Some((end, Some(Tag::Close(name))));
+    }
+    if inner.ends_with('/') || VOID_ELEMENTS.contains(&name.as_str()) {
+        return Some((end, None));
+    }
+    return Some((end, Some(Tag::Open(name))));
+}
+
+/**
+ * Split text into the pieces we may cut between.
+ */
+fn atoms(text: &str, format: Format) -> Vec<Atom> {
+    let mut atoms = Vec::new();
+    let mut i = 0;
+    while i < text.len() {
+        let c = text[i..].chars().next().unwrap();
+        let mut end = i + c.len_utf8();
+        let mut tag = None;
+        match c {
+            '[' | '!' if format == Format::Markdown => {
+                if let Some(x) = markdown_link(text, i) {
+                    end = x;
+                }
+            }
+            '<' if format == Format::Html => {
+                if let Some((x, t)) = html_tag(text, i) {
+                    end = x;
+                    tag = t;
+                }
+            }
+            '&' if format != Format::Plain => {
+                if let Some(x) = entity(text, i) {
+                    end = x;
+                }
+            }
+            _ => {}
+        }
+        atoms.push(Atom {
+            start: i,
+            end,
+            chars: text[i..end].chars().count(),
+            tag,
+        });
+        i = end;
+    }
+    return atoms;
+}
+
+/**
+ * The closing tags for the elements that are still open.
+ */
+fn closing_tags(open: &[String]) -> String {
+    return open
+        .iter()
+        .rev()
+        .map(|x| format!("</{}>", x))
+        .collect::<Vec<String>>()
+        .join("");
+}
+
+/**
+ * What is appended to truncated text, an ellipsis and a link to the full
+ * entry if there is one.
+ */
+fn suffix(link: Option<&str>, format: Format) -> String {
+    match (link, format) {
+        (Some(link), Format::Markdown) => return format!("… [read more]({})", link),
+        (Some(link), Format::Html) => {
+            return format!(
+                "… <a href=\"{}\">read more</a>",
+                link.replace('&', "&amp;").replace('"', "&quot;")
+            )
+        }
+        (Some(link), Format::Plain) => return format!("… read more: {}", link),
+        (None, _) => return "…".to_owned(),
+    }
+}
+
+/**
+ * Shorten text to at most `max_length` characters, including the ellipsis
+ * and the "read more" link that are appended when it is cut. Markdown links,
+ * HTML tags and entities are never cut in half, and HTML elements that are
+ * left open are closed again. The read more link is left out if there is no
+ * room for it.
+ */
+pub fn truncate(text: &str, max_length: usize, link: Option<&str>, format: Format) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_owned();
+    }
+    let mut end = suffix(link, format);
+    if end.chars().count() > max_length {
+        end = suffix(None, format);
+    }
+    // Not even the ellipsis fits
+    if end.chars().count() > max_length {
+        return "".to_owned();
+    }
+    let budget = max_length.saturating_sub(end.chars().count());
+
+    let mut open: Vec<String> = Vec::new();
+    let mut used = 0;
+    let mut cut = 0;
+    let mut last_space: Option<(usize, Vec<String>)> = None;
+    for atom in atoms(text, format) {
+        let mut next_open = open.clone();
+        match &atom.tag {
+            Some(Tag::Open(name)) => next_open.push(name.to_owned()),
+            Some(Tag::Close(name)) => {
+                if let Some(pos) = next_open.iter().rposition(|x| x == name) {
+                    next_open.truncate(pos);
+                }
+            }
+            None => {}
+        }
+        let closing = closing_tags(&next_open).chars().count();
+        if used + atom.chars + closing > budget {
+            break;
+        }
+        used += atom.chars;
+        open = next_open;
+        if text[atom.start..atom.end].trim().is_empty() {
+            last_space = Some((atom.start, open.clone()));
+        }
+        cut = atom.end;
+    }
+
+    // Rather give up the read more link than send nothing but the link
+    if cut == 0 && link.is_some() {
+        return truncate(text, max_length, None, format);
+    }
+
+    // Prefer cutting between words, unless that throws away most of the text
+    if let Some((space, space_open)) = last_space {
+        if space * 2 >= cut
+            && text[cut..]
+                .chars()
+                .next()
+                .is_some_and(|x| !x.is_whitespace())
+        {
+            cut = space;
+            open = space_open;
+        }
+    }
+    return format!("{}{}{}", text[..cut].trim_end(), closing_tags(&open), end);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(text: &str, max_length: usize, link: Option<&str>, format: Format, expected: &str) {
+        let res = truncate(text, max_length, link, format);
+        assert_eq!(res, expected);
+        assert!(res.chars().count() <= max_length, "{:?} is too long", res);
+    }
+
+    #[test]
+    fn short_text() {
+        check(
+            "short",
+            5,
+            Some("https://example.com"),
+            Format::Markdown,
+            "short",
+        );
+    }
+
+    #[test]
+    fn cuts_between_words() {
+        check("hello wonderful world", 10, None, Format::Plain, "hello…");
+        // Unless that throws away most of the text
+        check(
+            "hello wonderful world",
+            12,
+            None,
+            Format::Plain,
+            "hello wonde…",
+        );
+        check("hello world again", 12, None, Format::Plain, "hello world…");
+    }
+
+    #[test]
+    fn markdown_links() {
+        let text = "see [the link](https://example.com) now";
+        check(text, 10, None, Format::Markdown, "see…");
+        check(
+            text,
+            37,
+            None,
+            Format::Markdown,
+            "see [the link](https://example.com)…",
+        );
+        // Links are only special in markdown
+        check(text, 10, None, Format::Plain, "see [the…");
+    }
+
+    #[test]
+    fn entities() {
+        check(
+            "a&amp;b&amp;c&amp;d",
+            10,
+            None,
+            Format::Markdown,
+            "a&amp;b…",
+        );
+        check("a&amp;b&amp;c&amp;d", 10, None, Format::Plain, "a&amp;b&a…");
+        check("x&#39;y&#39;z", 8, None, Format::Html, "x&#39;y…");
+    }
+
+    #[test]
+    fn closes_open_tags() {
+        check(
+            "<p><b>bold text</b> and more</p>",
+            20,
+            None,
+            Format::Html,
+            "<p><b>bold</b></p>…",
+        );
+        check(
+            "<p>one<br>two <i>three</i> four</p>",
+            24,
+            None,
+            Format::Html,
+            "<p>one<br>two</p>…",
+        );
+    }
+
+    #[test]
+    fn read_more_link() {
+        check(
+            "one two three four five six",
+            40,
+            Some("https://x.example"),
+            Format::Markdown,
+            "one two three four five six",
+        );
+        check(
+            "one two three four five six seven eight nine ten",
+            40,
+            Some("https://x.example"),
+            Format::Markdown,
+            "one two… [read more](https://x.example)",
+        );
+        check(
+            "one two three four five six seven eight nine ten eleven twelve",
+            60,
+            Some("https://x.example/?a=1&b=2"),
+            Format::Html,
+            "one… <a href=\"https://x.example/?a=1&amp;b=2\">read more</a>",
+        );
+    }
+
+    #[test]
+    fn read_more_link_does_not_fit() {
+        // The link alone is longer than the maximum
+        check(
+            "one two three four five six seven",
+            15,
+            Some("https://x.example"),
+            Format::Markdown,
+            "one two three…",
+        );
+        // The link fits, but leaves no room for any text
+        check(
+            "[a](https://a.example) rest of the text",
+            24,
+            Some("https://x.example"),
+            Format::Markdown,
+            "[a](https://a.example)…",
+        );
+    }
+
+    #[test]
+    fn shorter_than_suffix() {
+        check(
+            "hello world",
+            1,
+            Some("https://x.example"),
+            Format::Plain,
+            "…",
+        );
+        check("hello world", 0, None, Format::Plain, "");
+    }
+}
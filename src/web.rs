@@ -0,0 +1,675 @@
+use crate::database::{self, FeedConf, Format};
+use crate::feeds::{self, FeedOptions};
+use crate::preview;
+use crate::rss_utils::{self, escape_html};
+use crate::server::State;
+
+use chrono::prelude::NaiveDateTime;
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use log::info;
+use mysql::Conn;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::task;
+
+/**
+ * How many entries the template preview renders.
+ */
+const PREVIEW_COUNT: usize = 3;
+
+/**
+ * How many deliveries are shown on the deliveries page, and on the page of a
+ * feed.
+ */
+const DELIVERIES_SHOWN: u32 = 100;
+const FEED_DELIVERIES_SHOWN: u32 = 10;
+
+const STYLE: &str = "
+body { font-family: sans-serif; margin: 0 auto; max-width: 1100px; padding: 0 1em; }
+nav { border-bottom: 1px solid #ccc; padding: 1em 0; }
+nav a { margin-right: 1em; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: .4em; text-align: left; vertical-align: top; }
+label { display: block; margin-top: .8em; font-weight: bold; }
+input[type=text], input[type=number], select, textarea { width: 100%; box-sizing: border-box; }
+textarea { height: 5em; font-family: monospace; }
+pre { white-space: pre-wrap; background: #f4f4f4; padding: .5em; }
+.error { color: #b00; }
+.ok { color: #070; }
+.muted { color: #777; }
+.columns { display: flex; gap: 2em; }
+.columns > * { flex: 1; min-width: 0; }
+";
+
+/**
+ * Renders the preview whenever the form changes, waiting until the user
+ * stops typing for a moment.
+ */
+const PREVIEW_SCRIPT: &str = "
+const form = document.getElementById('feed-form');
+const preview = document.getElementById('preview');
+let timer;
+function update() {
+  preview.innerHTML = '<p class=\"muted\">Loading…</p>';
+  fetch('/preview', { method: 'POST', body: new URLSearchParams(new FormData(form)) })
+    .then(res => res.text())
+    .then(html => preview.innerHTML = html);
+}
+form.addEventListener('input', () => {
+  clearTimeout(timer);
+  timer = setTimeout(update, 800);
+});
+update();
+";
+
+/**
+ * A full HTML page with the navigation.
+ */
+fn page(status: StatusCode, title: &str, body: &str) -> Response<Body> {
+    let html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{} - rss-watcher</title><style>{}</style></head><body>\
+         <nav><a href=\"/\">Feeds</a><a href=\"/feeds/new\">Add feed</a>\
+         <a href=\"/deliveries\">Deliveries</a></nav><h1>{}</h1>{}</body></html>",
+        escape_html(title),
+        STYLE,
+        escape_html(title),
+        body
+    );
+    return html_response(status, html);
+}
+
+fn html_response(status: StatusCode, html: String) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(html))
+        .unwrap();
+}
+
+/**
+ * A page with only an error message.
+ */
+fn error_page(status: StatusCode, message: &str) -> Response<Body> {
+    return page(
+        status,
+        status.canonical_reason().unwrap_or("Error"),
+        &format!("<p class=\"error\">{}</p>", escape_html(message)),
+    );
+}
+
+/**
+ * Send the browser to another page after a form was submitted.
+ */
+fn redirect(location: &str) -> Response<Body> {
+    return Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap();
+}
+
+fn time_display(time: Option<i64>) -> String {
+    return time
+        .and_then(|x| NaiveDateTime::from_timestamp_opt(x, 0))
+        .map(|x| x.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+        .unwrap_or_else(|| "never".to_owned());
+}
+
+/**
+ * The hidden form field every form must send along.
+ */
+fn csrf_field(state: &State) -> String {
+    return format!(
+        "<input type=\"hidden\" name=\"csrf\" value=\"{}\">",
+        state.csrf_token
+    );
+}
+
+/**
+ * Read a url encoded form from the body of a request.
+ */
+async fn read_form(req: Request<Body>) -> HashMap<String, String> {
+    let bytes = hyper::body::to_bytes(req.into_body())
+        .await
+        .unwrap_or_default();
+    return form_urlencoded::parse(&bytes).into_owned().collect();
+}
+
+/**
+ * The list of feeds with their state.
+ */
+fn feed_list(conn: &mut Conn, state: &State) -> Response<Body> {
+    let feeds;
+    match database::get_feeds(conn) {
+        Some(x) => feeds = x,
+        None => return error_page(StatusCode::INTERNAL_SERVER_ERROR, "Could not get feeds"),
+    }
+    let mut rows = String::new();
+    for feed in &feeds {
+        let status = match (&feed.last_error, feed.enabled) {
+            (_, false) => "<span class=\"muted\">disabled</span>".to_owned(),
+            (Some(e), true) => format!("<span class=\"error\">{}</span>", escape_html(e)),
+            (None, true) => "<span class=\"ok\">ok</span>".to_owned(),
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td><a href=\"/feeds/{}\">{}</a></td><td>{}</td><td>{}</td></tr>",
+            feed.id,
+            feed.id,
+            escape_html(&feed.url),
+            time_display(feed.last_fetch),
+            status
+        ));
+    }
+    let body = format!(
+        "<form method=\"post\" action=\"/fetch\">{}<button>Check all feeds now</button></form>\
+         <table><tr><th>ID</th><th>URL</th><th>Last fetch</th><th>Status</th></tr>{}</table>",
+        csrf_field(state),
+        rows
+    );
+    return page(StatusCode::OK, "Feeds", &body);
+}
+
+/**
+ * The values of a feed as they are shown in the form.
+ */
+fn feed_values(feed: &FeedConf) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    values.insert("url".to_owned(), feed.url.to_owned());
+    let target = feed.target.map(|x| x.to_string()).unwrap_or_default();
+    values.insert("target".to_owned(), target);
+    values.insert("push_url".to_owned(), feed.push_url.to_owned());
+    values.insert("push_token".to_owned(), feed.push_token.to_owned());
+    let template = feed.template.to_owned().unwrap_or_default();
+    values.insert("template".to_owned(), template);
+    values.insert("title".to_owned(), feed.title.to_owned());
+    values.insert("message".to_owned(), feed.message.to_owned());
+    values.insert("priority".to_owned(), feed.priority.to_string());
+    values.insert("format".to_owned(), feed.format.name().to_owned());
+    values.insert("timezone".to_owned(), feed.timezone.to_owned());
+    let max_length = feed.max_length.map(|x| x.to_string()).unwrap_or_default();
+    values.insert("max_length".to_owned(), max_length);
+    if feed.enabled {
+        values.insert("enabled".to_owned(), "on".to_owned());
+    }
+    return values;
+}
+
+/**
+ * Turn a submitted form into feed options. With the current values of the
+ * feed only the fields that were changed are set, so templates that come from
+ * a shared template are not copied into the feed.
+ */
+fn form_options(
+    form: &HashMap<String, String>,
+    current: Option<&HashMap<String, String>>,
+) -> Result<FeedOptions, String> {
+    let changed = |name: &str| -> Option<String> {
+        let value = form.get(name).map(|x| x.trim()).unwrap_or("");
+        let old = current.map(|x| x.get(name).map(|x| x.as_str()).unwrap_or(""));
+        if value.is_empty() || old == Some(value) {
+            return None;
+        }
+        return Some(value.to_owned());
+    };
+    // Emptying a field that can be unset clears it, the same as `""` in the API
+    let cleared = |name: &str| -> bool {
+        let value = form.get(name).map(|x| x.trim()).unwrap_or("");
+        let old = current.and_then(|x| x.get(name)).map(|x| x.as_str());
+        return value.is_empty() && old.is_some_and(|x| !x.is_empty());
+    };
+    let number = |name: &str| -> Result<Option<u32>, String> {
+        match changed(name) {
+            Some(x) => match x.parse::<u32>() {
+                Ok(x) => return Ok(Some(x)),
+                Err(_e) => return Err(format!("Invalid {} {:?}", name.replace('_', " "), x)),
+            },
+            None => return Ok(None),
+        }
+    };
+    let enabled = form.contains_key("enabled");
+    let was_enabled = current.map(|x| x.contains_key("enabled"));
+    return Ok(FeedOptions {
+        target: if cleared("target") {
+            Some(0)
+        } else {
+            number("target")?
+        },
+        push_url: changed("push_url"),
+        push_token: changed("push_token"),
+        title: if cleared("title") {
+            Some("".to_owned())
+        } else {
+            changed("title")
+        },
+        message: if cleared("message") {
+            Some("".to_owned())
+        } else {
+            changed("message")
+        },
+        template: if cleared("template") {
+            Some("".to_owned())
+        } else {
+            changed("template")
+        },
+        priority: number("priority")?,
+        format: changed("format"),
+        timezone: changed("timezone"),
+        max_length: if cleared("max_length") {
+            Some(0)
+        } else {
+            number("max_length")?
+        },
+        enabled: if was_enabled == Some(enabled) {
+            None
+        } else {
+            Some(enabled)
+        },
+    });
+}
+
+/**
+ * `<option>`s for a select, with the option matching `selected` selected.
+ */
+fn options(choices: &[(String, String)], selected: &str) -> String {
+    return choices
+        .iter()
+        .map(|(value, label)| {
+            format!(
+                "<option value=\"{}\"{}>{}</option>",
+                escape_html(value),
+                if value == selected { " selected" } else { "" },
+                escape_html(label)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("");
+}
+
+/**
+ * The form to add or edit a feed, with the live preview next to it.
+ */
+fn feed_form(
+    feed_id: Option<u32>,
+    values: &HashMap<String, String>,
+    error: Option<&str>,
+    conn: &mut Conn,
+    state: &State,
+) -> String {
+    let value = |name: &str| escape_html(values.get(name).map(|x| x.as_str()).unwrap_or(""));
+
+    let mut targets = vec![("".to_owned(), "None, use the push url and token".to_owned())];
+    for target in database::get_targets(conn).unwrap_or_default() {
+        targets.push((target.id.to_string(), target.name));
+    }
+    let mut templates = vec![("".to_owned(), "None".to_owned())];
+    for template in database::get_templates(conn).unwrap_or_default() {
+        templates.push((template.name.to_owned(), template.name));
+    }
+    let formats: Vec<(String, String)> = [Format::Markdown, Format::Html, Format::Plain]
+        .iter()
+        .map(|x| (x.name().to_owned(), x.name().to_owned()))
+        .collect();
+    let selected = |name: &str| {
+        values
+            .get(name)
+            .map(|x| x.as_str())
+            .unwrap_or("")
+            .to_owned()
+    };
+
+    let action = match feed_id {
+        Some(id) => format!("/feeds/{}", id),
+        None => "/feeds/new".to_owned(),
+    };
+    let id_field = match feed_id {
+        Some(id) => format!("<input type=\"hidden\" name=\"id\" value=\"{}\">", id),
+        None => "".to_owned(),
+    };
+    let error_html = match error {
+        Some(e) => format!("<p class=\"error\">{}</p>", escape_html(e)),
+        None => "".to_owned(),
+    };
+    return format!(
+        "{error}<div class=\"columns\"><form id=\"feed-form\" method=\"post\" action=\"{action}\">\
+         {csrf}{id}\
+         <label>URL</label><input type=\"text\" name=\"url\" value=\"{url}\" required>\
+         <label><input type=\"checkbox\" name=\"enabled\"{enabled}> Enabled</label>\
+         <label>Target</label><select name=\"target\">{targets}</select>\
+         <label>Push url</label><input type=\"text\" name=\"push_url\" value=\"{push_url}\">\
+         <label>Push token</label><input type=\"text\" name=\"push_token\" value=\"{push_token}\">\
+         <label>Shared template</label><select name=\"template\">{templates}</select>\
+         <label>Title</label><textarea name=\"title\">{title}</textarea>\
+         <label>Message</label><textarea name=\"message\">{message}</textarea>\
+         <label>Format</label><select name=\"format\">{formats}</select>\
+         <label>Priority</label><input type=\"number\" min=\"0\" max=\"10\" name=\"priority\" value=\"{priority}\">\
+         <label>Timezone</label><input type=\"text\" name=\"timezone\" value=\"{timezone}\">\
+         <label>Maximum length</label><input type=\"number\" min=\"1\" name=\"max_length\" value=\"{max_length}\">\
+         <p><button>Save</button></p></form>\
+         <div><h2>Preview</h2><div id=\"preview\"></div></div></div>\
+         <script>{script}</script>",
+        error = error_html,
+        action = action,
+        csrf = csrf_field(state),
+        id = id_field,
+        url = value("url"),
+        enabled = if values.contains_key("enabled") { " checked" } else { "" },
+        targets = options(&targets, &selected("target")),
+        push_url = value("push_url"),
+        push_token = value("push_token"),
+        templates = options(&templates, &selected("template")),
+        title = value("title"),
+        message = value("message"),
+        formats = options(&formats, &selected("format")),
+        priority = value("priority"),
+        timezone = value("timezone"),
+        max_length = value("max_length"),
+        script = PREVIEW_SCRIPT,
+    );
+}
+
+/**
+ * A table of deliveries, optionally only those of one feed.
+ */
+fn deliveries_table(feed_id: Option<u32>, limit: u32, conn: &mut Conn) -> String {
+    let deliveries;
+    match database::get_deliveries(feed_id, limit, conn) {
+        Some(x) => deliveries = x,
+        None => return "<p class=\"error\">Could not get deliveries</p>".to_owned(),
+    }
+    if deliveries.is_empty() {
+        return "<p class=\"muted\">Nothing sent yet</p>".to_owned();
+    }
+    let mut rows = String::new();
+    for delivery in deliveries {
+        let status = match delivery.status.as_str() {
+            "sent" => "<span class=\"ok\">sent</span>".to_owned(),
+            "failed" => format!(
+                "<span class=\"error\">failed: {}</span>",
+                escape_html(delivery.error.as_deref().unwrap_or(""))
+            ),
+            x => escape_html(x),
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td><a href=\"/feeds/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            time_display(Some(delivery.time)),
+            delivery.feed_id,
+            delivery.feed_id,
+            escape_html(&delivery.title),
+            delivery.priority,
+            status
+        ));
+    }
+    return format!(
+        "<table><tr><th>Time</th><th>Feed</th><th>Title</th><th>Priority</th><th>Status</th></tr>{}</table>",
+        rows
+    );
+}
+
+/**
+ * The page of a single feed, its form, last error and latest deliveries.
+ */
+fn feed_page(
+    feed: &FeedConf,
+    values: &HashMap<String, String>,
+    error: Option<&str>,
+    conn: &mut Conn,
+    state: &State,
+) -> Response<Body> {
+    let mut body = feed_form(Some(feed.id), values, error, conn, state);
+    body.push_str(&format!(
+        "<h2>State</h2><p>Last fetch: {}</p>",
+        time_display(feed.last_fetch)
+    ));
+    if let Some(e) = &feed.last_error {
+        body.push_str(&format!(
+            "<p class=\"error\">Last error ({}): {}</p>",
+            time_display(feed.last_error_at),
+            escape_html(e)
+        ));
+    }
+    body.push_str("<h2>Latest deliveries</h2>");
+    body.push_str(&deliveries_table(
+        Some(feed.id),
+        FEED_DELIVERIES_SHOWN,
+        conn,
+    ));
+    body.push_str(&format!(
+        "<h2>Remove</h2><form method=\"post\" action=\"/feeds/{}/delete\" \
+         onsubmit=\"return confirm('Remove this feed?')\">{}<button>Remove feed</button></form>",
+        feed.id,
+        csrf_field(state)
+    ));
+    let status = if error.is_some() {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+    return page(status, &format!("Feed {}", feed.id), &body);
+}
+
+/**
+ * Get a single feed, or the status and message saying why we could not.
+ */
+fn find_feed(feed_id: u32, conn: &mut Conn) -> Result<FeedConf, (StatusCode, &'static str)> {
+    let feeds = database::get_feeds(conn)
+        .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Could not get feeds"))?;
+    return feeds
+        .into_iter()
+        .find(|x| x.id == feed_id)
+        .ok_or((StatusCode::NOT_FOUND, "There is no such feed"));
+}
+
+async fn create_feed(
+    form: &HashMap<String, String>,
+    conn: &mut Conn,
+    state: &State,
+) -> Response<Body> {
+    let url = form.get("url").map(|x| x.trim()).unwrap_or("");
+    let res = match form_options(form, None) {
+        Ok(options) => feeds::create(url, &options, conn).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(Some(id)) => {
+            info!("Added feed {} {:?} through the web UI", id, url);
+            return redirect(&format!("/feeds/{}", id));
+        }
+        Ok(None) => return redirect("/"),
+        Err(e) => {
+            let body = feed_form(None, form, Some(&e), conn, state);
+            return page(StatusCode::BAD_REQUEST, "Add feed", &body);
+        }
+    }
+}
+
+async fn update_feed(
+    feed_id: u32,
+    form: &HashMap<String, String>,
+    conn: &mut Conn,
+    state: &State,
+) -> Response<Body> {
+    let feed;
+    match find_feed(feed_id, conn) {
+        Ok(x) => feed = x,
+        Err((status, message)) => return error_page(status, message),
+    }
+    let current = feed_values(&feed);
+    let url = form
+        .get("url")
+        .map(|x| x.trim().to_owned())
+        .filter(|x| !x.is_empty() && *x != feed.url);
+    let res = match form_options(form, Some(&current)) {
+        Ok(options) => feeds::update(feed_id, url, &options, conn).await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(true) => {
+            info!("Updated feed {} through the web UI", feed_id);
+            return redirect(&format!("/feeds/{}", feed_id));
+        }
+        Ok(false) => return error_page(StatusCode::NOT_FOUND, "There is no such feed"),
+        Err(e) => return feed_page(&feed, form, Some(&e), conn, state),
+    }
+}
+
+/**
+ * Render the latest entries of the feed in the form, with the templates and
+ * settings from the form. Returns an HTML fragment for the preview script.
+ */
+async fn preview(form: &HashMap<String, String>, conn: &mut Conn) -> Response<Body> {
+    let field = |name: &str| {
+        form.get(name)
+            .map(|x| x.trim().to_owned())
+            .filter(|x| !x.is_empty())
+    };
+    let error = |message: String| {
+        html_response(
+            StatusCode::OK,
+            format!("<p class=\"error\">{}</p>", escape_html(&message)),
+        )
+    };
+
+    let mut feed_conf = FeedConf::default();
+    if let Some(id) = field("id").and_then(|x| x.parse::<u32>().ok()) {
+        match find_feed(id, conn) {
+            Ok(x) => feed_conf = x,
+            Err((_, message)) => return error(message.to_owned()),
+        }
+    }
+    // Only fetch from the web, the preview must not read local files
+    let url = field("url").unwrap_or_default();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return error("Enter the http(s) url of the feed to see a preview".to_owned());
+    }
+    feed_conf.url = url.to_owned();
+    if let Some(x) = field("title") {
+        feed_conf.title = x;
+    }
+    if let Some(x) = field("message") {
+        feed_conf.message = x;
+    }
+    if let Some(x) = field("format").and_then(|x| Format::parse(&x)) {
+        feed_conf.format = x;
+    }
+    feed_conf.max_length = field("max_length").and_then(|x| x.parse::<u32>().ok());
+    if let Err(e) = rss_utils::validate_templates(&feed_conf) {
+        return error(e);
+    }
+
+    let mut feed;
+    match rss_utils::load_feed(&url).await {
+        Ok(x) => feed = x,
+        Err(e) => return error(format!("Could not load feed ({})", e)),
+    }
+    let notifications = preview::render_latest(&mut feed, &feed_conf, PREVIEW_COUNT);
+    if notifications.is_empty() {
+        return error("The feed has no entries".to_owned());
+    }
+    let mut html = format!(
+        "<p class=\"muted\">Latest {} entries, as {}</p>",
+        notifications.len(),
+        feed_conf.format.name()
+    );
+    for (_, notification) in notifications {
+        html.push_str(&format!(
+            "<h3>{}</h3><pre>{}</pre><p class=\"muted\">Priority {}</p>",
+            escape_html(&notification.title),
+            escape_html(&notification.message),
+            notification.priority
+        ));
+        if let Some(x) = &notification.image {
+            html.push_str(&format!(
+                "<img src=\"{}\" alt=\"\" style=\"max-width: 100%\">",
+                escape_html(x)
+            ));
+        }
+    }
+    return html_response(StatusCode::OK, html);
+}
+
+/**
+ * Handle a request to the web UI, the token is already checked.
+ */
+pub async fn handle(req: Request<Body>, state: Arc<State>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = req.uri().path().trim_end_matches('/').to_owned();
+    let parts: Vec<&str> = path.split('/').skip(1).collect();
+    let feed_id = parts.get(1).and_then(|x| x.parse::<u32>().ok());
+
+    let mut form = HashMap::new();
+    if method == Method::POST {
+        form = read_form(req).await;
+        if form.get("csrf") != Some(&state.csrf_token) {
+            return error_page(
+                StatusCode::FORBIDDEN,
+                "Invalid form, reload the page and try again",
+            );
+        }
+    } else if method != Method::GET {
+        return error_page(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    if method == Method::POST && parts == ["fetch"] {
+        info!("Fetch requested through the web UI");
+        state.fetch_now.notify_one();
+        return redirect("/");
+    }
+
+    // The mysql calls block, so the request is handled on the blocking thread
+    // pool, instead of holding up the workers serving the other requests
+    let rt = Handle::current();
+    let res = task::spawn_blocking(move || {
+        let mut conn = database::new_conn()?;
+        let parts: Vec<&str> = path.split('/').skip(1).collect();
+        let res = rt.block_on(async {
+            match (&method, parts.as_slice(), feed_id) {
+                (&Method::GET, [], _) => return feed_list(&mut conn, &state),
+                (&Method::GET, ["feeds", "new"], _) => {
+                    let mut values = feed_values(&FeedConf::default());
+                    values.insert("url".to_owned(), "".to_owned());
+                    let body = feed_form(None, &values, None, &mut conn, &state);
+                    return page(StatusCode::OK, "Add feed", &body);
+                }
+                (&Method::POST, ["feeds", "new"], _) => {
+                    return create_feed(&form, &mut conn, &state).await
+                }
+                (&Method::GET, ["feeds", _], Some(id)) => match find_feed(id, &mut conn) {
+                    Ok(feed) => {
+                        return feed_page(&feed, &feed_values(&feed), None, &mut conn, &state)
+                    }
+                    Err((status, message)) => return error_page(status, message),
+                },
+                (&Method::POST, ["feeds", _], Some(id)) => {
+                    return update_feed(id, &form, &mut conn, &state).await
+                }
+                (&Method::POST, ["feeds", _, "delete"], Some(id)) => {
+                    if !database::delete_feed(id, &mut conn) {
+                        return error_page(StatusCode::NOT_FOUND, "There is no such feed");
+                    }
+                    info!("Removed feed {} through the web UI", id);
+                    return redirect("/");
+                }
+                (&Method::POST, ["preview"], _) => return preview(&form, &mut conn).await,
+                (&Method::GET, ["deliveries"], _) => {
+                    let body = deliveries_table(None, DELIVERIES_SHOWN, &mut conn);
+                    return page(StatusCode::OK, "Deliveries", &body);
+                }
+                _ => return error_page(StatusCode::NOT_FOUND, "There is no such page"),
+            }
+        });
+        return Some(res);
+    })
+    .await;
+    match res {
+        Ok(Some(x)) => return x,
+        _ => {
+            return error_page(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not connect to the database",
+            )
+        }
+    }
+}